@@ -0,0 +1,411 @@
+use std::{
+    collections::HashMap,
+    fs::File,
+    io::{BufRead, BufReader, BufWriter, Write},
+    path::PathBuf,
+};
+
+use clap::{Args, Parser, Subcommand};
+use color_eyre::eyre::{eyre, Result};
+use futures::TryStreamExt;
+use serde::{Deserialize, Serialize};
+use tracing::info;
+
+use crate::{
+    config::Config,
+    database::STDatabase,
+    export::{check_resolution, ExportFormat, MinuteExport, ParquetExport, PlayerColumn},
+    hour::{HourDef, DEFAULT_RESOLUTION},
+    peaks::{PeakId, Peaks},
+    rollup::RollupPeriod,
+    server,
+    tracker::{NameMapWrapper, SeenIndex, StatusTracker},
+    utils::{HourTimestamp, MinuteTimestamp},
+};
+
+const DEFAULT_CONFIG: &str = "./statustracker.toml";
+/// Number of days of rollups backfilled at once by `migrate`
+const MIGRATE_CHUNK_DAYS: u64 = 30;
+
+#[derive(Parser, Debug)]
+#[command(version, about, args_conflicts_with_subcommands = true)]
+pub struct Cli {
+    #[command(subcommand)]
+    pub command: Option<Command>,
+    /// Path to the config, to start the server without a subcommand
+    pub config: Option<PathBuf>,
+}
+
+#[derive(Args, Debug)]
+pub struct ConfigArg {
+    /// Path to the config
+    #[arg(short, long, default_value = DEFAULT_CONFIG)]
+    pub config: PathBuf,
+}
+
+#[derive(Subcommand, Debug)]
+pub enum Command {
+    /// Start the server and the tracker
+    Serve(ConfigArg),
+    /// Poll the source once and print the record, without writing anything
+    PollOnce(ConfigArg),
+    /// Check that the config is valid
+    CheckConfig(ConfigArg),
+    /// Write the name map and stored hours as NDJSON, for `import`
+    Export {
+        #[command(flatten)]
+        config: ConfigArg,
+        /// First hour timestamp to export
+        #[arg(long)]
+        from: Option<HourTimestamp>,
+        /// Last hour timestamp to export
+        #[arg(long)]
+        to: Option<HourTimestamp>,
+        /// File to write to, instead of stdout
+        #[arg(short, long)]
+        output: Option<PathBuf>,
+    },
+    /// Write every minute in a range as a CSV or NDJSON row
+    ExportMinutes {
+        #[command(flatten)]
+        config: ConfigArg,
+        /// First minute timestamp to export
+        #[arg(long)]
+        from: MinuteTimestamp,
+        /// Last minute timestamp to export
+        #[arg(long)]
+        to: MinuteTimestamp,
+        #[arg(long, value_enum, default_value_t = ExportFormat::Csv)]
+        format: ExportFormat,
+        /// Add a column listing the online players
+        #[arg(long, value_enum, default_value_t = PlayerColumn::None)]
+        players: PlayerColumn,
+        /// Seconds between rows, for hours sampled more often than every minute
+        #[arg(long, default_value_t = DEFAULT_RESOLUTION)]
+        resolution: u32,
+        /// File to write to, instead of stdout
+        #[arg(short, long)]
+        output: Option<PathBuf>,
+    },
+    /// Write every minute in a range as Parquet files of counts and of who was online,
+    /// partitioned by month
+    ExportParquet {
+        #[command(flatten)]
+        config: ConfigArg,
+        /// First minute timestamp to export
+        #[arg(long)]
+        from: MinuteTimestamp,
+        /// Last minute timestamp to export
+        #[arg(long)]
+        to: MinuteTimestamp,
+        /// Directory to write to
+        #[arg(short, long)]
+        output: PathBuf,
+    },
+    /// Read the output of `export` into the database, replacing hours that already exist,
+    /// then rebuild what is derived from them like `migrate`.
+    /// The server should not be running.
+    Import {
+        #[command(flatten)]
+        config: ConfigArg,
+        /// File to read from
+        input: PathBuf,
+    },
+    /// Check every stored hour for problems
+    Verify(ConfigArg),
+    /// Rebuild rollups, the seen index and peaks from every stored hour.
+    /// The server should not be running.
+    Migrate(ConfigArg),
+}
+
+/// A line of the output of `export`
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+#[serde(tag = "type", content = "data", rename_all = "snake_case")]
+pub enum BackupLine {
+    NameMap(NameMapWrapper),
+    Hour(HourDef),
+}
+
+impl Cli {
+    pub async fn run(self) -> Result<()> {
+        match self.command {
+            None => serve(self.config.unwrap_or_else(|| DEFAULT_CONFIG.into())).await,
+            Some(Command::Serve(a)) => serve(a.config).await,
+            Some(Command::PollOnce(a)) => poll_once(&Config::load(&a.config)?).await,
+            Some(Command::CheckConfig(a)) => {
+                let config = Config::load(&a.config)?;
+                println!(
+                    "Config is valid: {} categories, {} webhooks, polling every {}s",
+                    config.categories.len(),
+                    config.webhooks.len(),
+                    config.sample_interval
+                );
+                Ok(())
+            }
+            Some(Command::Export {
+                config,
+                from,
+                to,
+                output,
+            }) => export(&Config::load(&config.config)?, from, to, output).await,
+            Some(Command::ExportMinutes {
+                config,
+                from,
+                to,
+                format,
+                players,
+                resolution,
+                output,
+            }) => {
+                check_resolution(resolution)?;
+                let config = Config::load(&config.config)?;
+                let mut export = MinuteExport::new(
+                    format,
+                    players,
+                    config.categories.keys().cloned().collect(),
+                    resolution,
+                );
+                export.lookup_names = true;
+                export_minutes(&config, export, from, to, output).await
+            }
+            Some(Command::ExportParquet {
+                config,
+                from,
+                to,
+                output,
+            }) => {
+                let config = Config::load(&config.config)?;
+                let database = STDatabase::connect(&config).await?;
+                let name_map = database.get_name_map().await?;
+                ParquetExport::new(config.categories.keys().cloned().collect(), output)
+                    .write(&database, &name_map, from, to)
+                    .await
+            }
+            Some(Command::Import { config, input }) => {
+                import(&Config::load(&config.config)?, input).await
+            }
+            Some(Command::Verify(a)) => verify(&Config::load(&a.config)?).await,
+            Some(Command::Migrate(a)) => migrate(&Config::load(&a.config)?).await,
+        }
+    }
+}
+
+async fn serve(path: PathBuf) -> Result<()> {
+    let config = Config::load(&path)?;
+    server::start_server(StatusTracker::new(config).await?, path).await
+}
+
+async fn poll_once(config: &Config) -> Result<()> {
+    let names = config.pull_from_dynmap().await?;
+    let database = STDatabase::connect(config).await?;
+    let mut name_map = database.get_name_map().await?;
+    let ids = name_map.update_name_map(names).await?;
+    let record = config.split_into_categories(ids);
+    println!("{}", serde_json::to_string_pretty(&record)?);
+    Ok(())
+}
+
+async fn export(
+    config: &Config,
+    from: Option<HourTimestamp>,
+    to: Option<HourTimestamp>,
+    output_path: Option<PathBuf>,
+) -> Result<()> {
+    let database = STDatabase::connect(config).await?;
+    let mut out = output(output_path)?;
+    let name_map = database.get_name_map().await?;
+    serde_json::to_writer(&mut out, &BackupLine::NameMap(name_map))?;
+    writeln!(out)?;
+    let mut hours = database.find_hour_defs(from, to).await?;
+    let mut count = 0u64;
+    while let Some(hour) = hours.try_next().await? {
+        serde_json::to_writer(&mut out, &BackupLine::Hour(hour))?;
+        writeln!(out)?;
+        count += 1;
+    }
+    out.flush()?;
+    info!(count, "Exported hours");
+    Ok(())
+}
+
+fn output(path: Option<PathBuf>) -> Result<Box<dyn Write + Send>> {
+    Ok(match path {
+        Some(path) => Box::new(BufWriter::new(File::create(path)?)),
+        None => Box::new(BufWriter::new(std::io::stdout())),
+    })
+}
+
+async fn export_minutes(
+    config: &Config,
+    mut export: MinuteExport,
+    from: MinuteTimestamp,
+    to: MinuteTimestamp,
+    output_path: Option<PathBuf>,
+) -> Result<()> {
+    let database = STDatabase::connect(config).await?;
+    let name_map = database.get_name_map().await?;
+    let mut out = output(output_path)?;
+    if let Some(header) = export.header() {
+        out.write_all(header.as_bytes())?;
+    }
+    for (from, to) in MinuteExport::chunks(from, to) {
+        let rows = export.rows(&database, &name_map, from, to).await?;
+        out.write_all(rows.as_bytes())?;
+    }
+    out.flush()?;
+    Ok(())
+}
+
+/// The longer of two name maps, if one starts with the other
+fn merge_name_maps(current: NameMapWrapper, new: NameMapWrapper) -> Result<NameMapWrapper> {
+    if new.data.starts_with(&current.data) {
+        Ok(new)
+    } else if current.data.starts_with(&new.data) {
+        Ok(current)
+    } else {
+        Err(eyre!(
+            "The imported name map does not match the one in the database"
+        ))
+    }
+}
+
+async fn import(config: &Config, input: PathBuf) -> Result<()> {
+    let database = STDatabase::connect(config).await?;
+    let mut name_map = database.get_name_map().await?;
+    let mut count = 0u64;
+    for (i, line) in BufReader::new(File::open(input)?).lines().enumerate() {
+        let line = line?;
+        if line.trim().is_empty() {
+            continue;
+        }
+        match serde_json::from_str(&line).map_err(|e| eyre!("Line {}: {e}", i + 1))? {
+            BackupLine::NameMap(new) => {
+                name_map = merge_name_maps(name_map, new)?;
+                database.save_name_map(&name_map).await?;
+            }
+            BackupLine::Hour(hour) => {
+                let problems = hour.problems(name_map.data.len());
+                if !problems.is_empty() {
+                    return Err(eyre!("Line {}: {}", i + 1, problems.join(", ")));
+                }
+                database.save_hour_def(&hour).await?;
+                count += 1;
+            }
+        }
+    }
+    println!("Imported {count} hours");
+    if count == 0 {
+        return Ok(());
+    }
+    migrate(config).await
+}
+
+async fn verify(config: &Config) -> Result<()> {
+    let database = STDatabase::connect(config).await?;
+    let players = database.get_name_map().await?.data.len();
+    let mut hours = database.find_hour_defs(None, None).await?;
+    let (mut count, mut bad) = (0u64, 0u64);
+    while let Some(hour) = hours.try_next().await? {
+        count += 1;
+        let problems = hour.problems(players);
+        if !problems.is_empty() {
+            bad += 1;
+            for problem in problems {
+                println!("Hour {}: {problem}", hour._id);
+            }
+        }
+    }
+    println!("Checked {count} hours, {bad} with problems");
+    if bad != 0 {
+        return Err(eyre!("{bad} hours have problems"));
+    }
+    Ok(())
+}
+
+async fn migrate(config: &Config) -> Result<()> {
+    let database = STDatabase::connect(config).await?;
+    let players = database.get_name_map().await?.data.len();
+    let mut seen = SeenIndex::default();
+    let mut peaks: HashMap<PeakId, Peaks> = HashMap::new();
+
+    info!("Rebuilding seen index and peaks");
+    let range = database
+        .for_each_record(players, |min_ts, record| {
+            seen.update(record.all.iter().copied(), min_ts);
+            Peaks::update_all(&mut peaks, record, min_ts);
+        })
+        .await?;
+    database.save_seen(&seen).await?;
+    for peaks in peaks.values() {
+        database.save_peaks(peaks).await?;
+    }
+
+    let Some((first, last)) = range else {
+        println!("No hours to migrate");
+        return Ok(());
+    };
+    info!("Rebuilding rollups");
+    let (first, last) = (u64::from(first) * 60, u64::from(last) * 60 + 59);
+    database.delete_rollups(first, last).await?;
+    let chunk = RollupPeriod::Day.minutes() * MIGRATE_CHUNK_DAYS;
+    let mut from = RollupPeriod::Day.start_of(first);
+    while from <= last {
+        let to = (from + chunk - 1).min(last);
+        database.get_rollups(RollupPeriod::Day, from, to).await?;
+        from += chunk;
+    }
+    println!(
+        "Rebuilt the seen index of {} players, {} peaks and the rollups",
+        seen.first.iter().flatten().count(),
+        peaks.len()
+    );
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        collections::{HashMap, HashSet},
+        sync::Arc,
+    };
+
+    use crate::{
+        cli::{merge_name_maps, BackupLine},
+        hour::{AbsRecord, Hour, HourDef},
+        tracker::NameMapWrapper,
+    };
+
+    #[test]
+    pub fn backup_round_trip() {
+        let mut hour = Hour::new(5);
+        hour.records[3] = Some(Arc::new(AbsRecord {
+            all: HashSet::from([0, 1]),
+            categories: HashMap::from([("staff".into(), HashSet::from([1]))]),
+        }));
+        let line = BackupLine::Hour(HourDef::from(hour));
+        let json = serde_json::to_string(&line).unwrap();
+        assert_eq!(serde_json::from_str::<BackupLine>(&json).unwrap(), line);
+
+        let name_map = |n: u8| NameMapWrapper {
+            _id: 0,
+            data: (0..n).map(|a| [a; 16]).collect(),
+        };
+        assert_eq!(
+            merge_name_maps(name_map(1), name_map(2))
+                .unwrap()
+                .data
+                .len(),
+            2
+        );
+        assert_eq!(
+            merge_name_maps(name_map(2), name_map(1))
+                .unwrap()
+                .data
+                .len(),
+            2
+        );
+        let mut other = name_map(2);
+        other.data[0] = [9; 16];
+        assert!(merge_name_maps(name_map(2), other).is_err());
+    }
+}
@@ -1,6 +1,6 @@
-use std::collections::HashMap;
+use std::{collections::HashMap, path::Path};
 
-use color_eyre::eyre::eyre;
+use color_eyre::eyre::{eyre, Result};
 use itertools::Itertools;
 use serde::{Deserialize, Serialize};
 use serde_json::{Map, Value};
@@ -9,12 +9,71 @@ use tracing::{debug, info, trace};
 use url::Url;
 use uuid::Uuid;
 
-use crate::{hour::AbsRecord, utils::Category};
+use crate::{
+    hour::{AbsRecord, DEFAULT_RESOLUTION},
+    peaks::PeakPeriod,
+    utils::Category,
+};
+
+#[derive(Deserialize, Serialize, Debug, Clone, Default, PartialEq, Eq)]
+pub struct CategoryInfo {
+    pub display_name: Option<SmolStr>,
+    pub colour: Option<SmolStr>,
+    pub description: Option<String>,
+    pub order: Option<i64>,
+    #[serde(default)]
+    pub hidden: bool,
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone, Copy, PartialEq, Eq, Hash)]
+#[serde(rename_all = "snake_case")]
+pub enum WebhookEvent {
+    PlayerJoined,
+    CoverageLost,
+    PeakBroken,
+    PollFailed,
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone, PartialEq, Eq)]
+pub struct WebhookConfig {
+    pub url: Url,
+    pub events: Vec<WebhookEvent>,
+    /// Players to send `player_joined` for, or every player if empty
+    #[serde(default)]
+    pub players: Vec<Uuid>,
+    /// Categories to send `coverage_lost` for, or every category if empty
+    #[serde(default)]
+    pub categories: Vec<Category>,
+    /// Periods to send `peak_broken` for
+    #[serde(default = "default_peak_periods")]
+    pub peak_periods: Vec<PeakPeriod>,
+    /// Seconds a peak has to stop rising for before `peak_broken` is sent, with the latest peak
+    #[serde(default = "default_peak_debounce")]
+    pub peak_debounce: u64,
+    /// Number of failed polls in a row to send `poll_failed` at
+    #[serde(default = "default_failed_polls")]
+    pub failed_polls: u32,
+    /// Message templates, with `{field}` replaced by fields of the event
+    #[serde(default)]
+    pub templates: HashMap<WebhookEvent, String>,
+}
+
+const fn default_failed_polls() -> u32 {
+    3
+}
+fn default_peak_periods() -> Vec<PeakPeriod> {
+    vec![PeakPeriod::AllTime]
+}
+const fn default_peak_debounce() -> u64 {
+    600
+}
 
 #[derive(Deserialize, Serialize)]
 pub struct Config {
     #[serde(default)]
     pub categories: HashMap<Category, Vec<Uuid>>,
+    #[serde(default)]
+    pub category_info: HashMap<Category, CategoryInfo>,
     pub dynmap_link: Url,
     pub mongodb_uri: SmolStr,
     pub database_name: SmolStr,
@@ -22,14 +81,67 @@ pub struct Config {
     pub hosted_over_http: bool,
     #[serde(default)]
     pub no_write: bool,
+    #[serde(default)]
+    pub maintenance: bool,
+    /// Number of seconds between each poll
+    #[serde(default = "default_sample_interval")]
+    pub sample_interval: u32,
+    #[serde(default)]
+    pub webhooks: Vec<WebhookConfig>,
+}
+
+const fn default_sample_interval() -> u32 {
+    DEFAULT_RESOLUTION
 }
 
 impl Config {
+    #[tracing::instrument]
+    pub fn load(path: &Path) -> Result<Self> {
+        info!("Loading config");
+        let file = std::fs::read_to_string(path)
+            .map_err(|e| eyre!("Error opening {}: {e}", path.display()))?;
+        let config: Self = toml::from_str(&file)?;
+        config.validate()?;
+        Ok(config)
+    }
+
+    #[tracing::instrument(skip(self))]
+    pub fn validate(&self) -> Result<()> {
+        debug!("Checking for `all` category");
+        if self.categories.keys().contains::<Category>(&"all".into()) {
+            return Err(eyre!("Category named `all` found"));
+        }
+        debug!("Checking sample interval");
+        if self.sample_interval == 0
+            || !3600u32.is_multiple_of(self.sample_interval)
+            || (!60u32.is_multiple_of(self.sample_interval)
+                && !self.sample_interval.is_multiple_of(60))
+        {
+            return Err(eyre!(
+                "`sample_interval` must divide evenly into a minute, or be a whole number of minutes that divides evenly into an hour"
+            ));
+        }
+        debug!("Checking category info");
+        if let Some(cat) = self
+            .category_info
+            .keys()
+            .find(|cat| !self.categories.contains_key(*cat))
+        {
+            return Err(eyre!("Info given for unknown category `{cat}`"));
+        }
+        debug!("Checking webhooks");
+        if self.webhooks.iter().any(|a| a.failed_polls == 0) {
+            return Err(eyre!("`failed_polls` of webhooks must be at least 1"));
+        }
+        Ok(())
+    }
+
     #[tracing::instrument(skip(self))]
-    pub async fn pull_from_dynmap(&self) -> color_eyre::Result<Vec<SmolStr>> {
+    pub async fn pull_from_dynmap(&self) -> Result<Vec<SmolStr>> {
         info!("Pulling player list from Dynmap");
         let json: Map<String, Value> = reqwest::get(self.dynmap_link.to_owned())
             .await?
+            .error_for_status()?
             .json()
             .await?;
         trace!(?json);
@@ -47,7 +159,7 @@ impl Config {
                     .ok_or_else(|| eyre!("Field `account` in player object is not string"))
             })
             .map_ok(std::convert::Into::into)
-            .collect::<color_eyre::Result<Vec<_>, _>>()
+            .collect::<Result<Vec<_>, _>>()
     }
 
     #[tracing::instrument(skip(self))]
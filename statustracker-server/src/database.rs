@@ -1,39 +1,145 @@
-use std::{sync::Arc, time::SystemTime};
+use std::{
+    collections::{HashMap, HashSet},
+    sync::Arc,
+    time::SystemTime,
+};
 
 use color_eyre::eyre::Result;
-use futures::StreamExt;
+use futures::{StreamExt, TryStreamExt};
 use itertools::Itertools;
 use mongodb::{
     bson::{doc, to_bson},
-    Database,
+    options::ClientOptions,
+    Client, Cursor, Database,
 };
+use once_cell::sync::Lazy;
 use rayon::prelude::*;
-use tracing::info;
+use tokio::sync::RwLock;
+use tracing::{debug, info, warn};
 
 use crate::{
-    hour::{AbsRecord, Hour, HourDef, RollingAvgRecord},
-    tracker::NameMapWrapper,
-    utils::{HourTimestamp, MinuteTimestamp},
+    config::Config,
+    hour::{
+        AbsRecord, BandRecord, Gap, GapReason, Hour, HourDef, RollingAvgRecord, DEFAULT_RESOLUTION,
+    },
+    outage::{DayUptime, Outage},
+    peaks::{PeakId, PeakPeriod, Peaks},
+    rollup::{Rollup, RollupId, RollupPeriod},
+    stats::{copresence, intervals, ActivePlayers, Period, Playtime, UniqueCache, UniqueCount},
+    tracker::{NameMapWrapper, SeenIndex},
+    utils::{get_minute_timestamp, HourTimestamp, MinuteTimestamp, SecondTimestamp},
 };
 
+/// Unique player counts of periods that are over, cleared for hours that are written again
+pub static UNIQUE_CACHE: Lazy<RwLock<UniqueCache>> =
+    Lazy::new(|| RwLock::new(UniqueCache::new(UNIQUE_CACHE_CAPACITY)));
+const UNIQUE_CACHE_CAPACITY: usize = 4096;
+
+/// Rolling averages with windows at least this wide are calculated from hour rollups
+const HOUR_ROLLUP_MIN_DELTA: u64 = 60 * 6;
+/// Rolling averages with windows at least this wide are calculated from day rollups
+const DAY_ROLLUP_MIN_DELTA: u64 = 60 * 24 * 7;
+
 pub struct STDatabase(pub Database);
 
 impl STDatabase {
-    #[tracing::instrument(skip(self))]
-    pub async fn add_record(&self, record: AbsRecord) -> Result<()> {
-        let min_ts: MinuteTimestamp = SystemTime::now()
-            .duration_since(SystemTime::UNIX_EPOCH)
-            .unwrap()
-            .as_secs()
-            / 60;
-        let h_ts = (min_ts / 60) as HourTimestamp;
-        let mut hour = self
-            .get_hour(h_ts)
+    #[tracing::instrument(skip_all)]
+    pub async fn connect(config: &Config) -> Result<Self> {
+        info!("Creating client");
+        let mongodb_uri = if let Ok(mongodb_uri) = std::env::var(&config.mongodb_uri) {
+            mongodb_uri
+        } else {
+            config.mongodb_uri.to_string()
+        };
+        let client = Client::with_options(ClientOptions::parse(mongodb_uri).await?)?;
+        Ok(Self(client.database(&config.database_name)))
+    }
+    pub async fn get_name_map(&self) -> Result<NameMapWrapper> {
+        info!("Retrieving name_map");
+        Ok(self
+            .0
+            .collection("name_map")
+            .find_one(doc! {"_id": 0u32})
             .await?
-            .unwrap_or_else(|| Hour::new(h_ts));
-        info!(min_ts, "Adding record");
-        hour.records[(min_ts - u64::from(h_ts) * 60) as usize] = Some(Arc::new(record));
+            .unwrap_or_default())
+    }
+    /// The seen index, or `None` if it has never been saved
+    pub async fn get_seen(&self) -> Result<Option<SeenIndex>> {
+        info!("Retrieving seen index");
+        Ok(self
+            .0
+            .collection("seen")
+            .find_one(doc! {"_id": 0u32})
+            .await?)
+    }
+    /// Builds the seen index from every stored hour
+    pub async fn rebuild_seen(&self, players: usize) -> Result<SeenIndex> {
+        let mut seen = SeenIndex::default();
+        self.for_each_record(players, |min_ts, record| {
+            seen.update(record.all.iter().copied(), min_ts);
+        })
+        .await?;
+        Ok(seen)
+    }
+    /// Builds the peaks of every period from every stored hour
+    pub async fn rebuild_peaks(&self, players: usize) -> Result<HashMap<PeakId, Peaks>> {
+        let mut peaks = HashMap::new();
+        self.for_each_record(players, |min_ts, record| {
+            Peaks::update_all(&mut peaks, record, min_ts);
+        })
+        .await?;
+        Ok(peaks)
+    }
+    /// Gets an hour to write samples into, converting it to `resolution` if it was stored with a different one
+    async fn get_hour_to_write(&self, h_ts: HourTimestamp, resolution: u32) -> Result<Hour> {
+        Ok(match self.get_hour(h_ts).await? {
+            Some(hour) if hour.resolution != resolution => {
+                info!(
+                    h_ts,
+                    from = hour.resolution,
+                    to = resolution,
+                    "Changing resolution of hour"
+                );
+                hour.resample(resolution)
+            }
+            Some(hour) => hour,
+            None => Hour::with_resolution(h_ts, resolution),
+        })
+    }
+
+    #[tracing::instrument(skip(self))]
+    pub async fn add_record(
+        &self,
+        record: AbsRecord,
+        ts: SecondTimestamp,
+        resolution: u32,
+    ) -> Result<()> {
+        let h_ts = (ts / 3600) as HourTimestamp;
+        let mut hour = self.get_hour_to_write(h_ts, resolution).await?;
+        info!(ts, "Adding record");
+        hour.set_record(ts, record);
+        let rollup = Rollup::from_hour(&hour);
         self.save_hour(hour).await?;
+        self.update_rollups(rollup).await?;
+        Ok(())
+    }
+
+    /// Records why the samples from `from` to `to` inclusive have no record.
+    /// Samples that already have a record are left alone.
+    #[tracing::instrument(skip(self))]
+    pub async fn add_gaps(
+        &self,
+        from: SecondTimestamp,
+        to: SecondTimestamp,
+        reason: GapReason,
+        resolution: u32,
+    ) -> Result<()> {
+        for h_ts in (from / 3600) as HourTimestamp..=(to / 3600) as HourTimestamp {
+            let mut hour = self.get_hour_to_write(h_ts, resolution).await?;
+            info!(h_ts, "Adding gaps");
+            hour.set_gaps(from, to, reason);
+            self.save_hour(hour).await?;
+        }
         Ok(())
     }
 
@@ -49,6 +155,97 @@ impl STDatabase {
             .await?;
         Ok(())
     }
+    #[tracing::instrument(skip_all)]
+    pub async fn save_seen(&self, seen: &SeenIndex) -> Result<()> {
+        info!("Saving seen index");
+        let mut b = to_bson(seen)?;
+        b.as_document_mut().unwrap().remove("_id");
+        self.0
+            .collection::<SeenIndex>("seen")
+            .update_one(doc! {"_id": 0u32}, doc! {"$set": b})
+            .upsert(true)
+            .await?;
+        Ok(())
+    }
+    /// The spread of player counts in the same windows as [`Self::get_rolling_avg`]
+    pub async fn get_bands(
+        &self,
+        from: MinuteTimestamp,
+        to: MinuteTimestamp,
+        delta: u64,
+        percentiles: &[u8],
+    ) -> Result<Vec<Option<BandRecord>>> {
+        if let Some(period) = Self::rollup_period_for(delta) {
+            return self
+                .map_rollup_windows(period, from, to, delta, |rollups| {
+                    BandRecord::from_rollups(rollups, percentiles)
+                })
+                .await;
+        }
+        let mins = self
+            .get_minutes(from.saturating_sub(delta), to.saturating_add(delta))
+            .await?;
+        if delta == 0 {
+            return Ok(mins
+                .into_par_iter()
+                .map(|a| a.and_then(|a| BandRecord::new(&[&a], percentiles)))
+                .collect());
+        }
+        let udelta = delta as usize;
+        let step = ((to - from) / 1000 + 1) as usize;
+        Ok((udelta..mins.len() - udelta)
+            .into_par_iter()
+            .step_by(step)
+            .map(|i| {
+                let window = mins[i.saturating_sub(udelta)..=i.saturating_add(udelta)]
+                    .iter()
+                    .filter_map(|a| a.as_deref())
+                    .collect::<Vec<_>>();
+                vec![BandRecord::new(&window, percentiles)]
+            })
+            .intersperse(vec![None; step - 1])
+            .flatten()
+            .collect())
+    }
+    /// The rollup period that rolling averages and bands with windows of `delta` are calculated from, if any
+    const fn rollup_period_for(delta: u64) -> Option<RollupPeriod> {
+        if delta >= DAY_ROLLUP_MIN_DELTA {
+            Some(RollupPeriod::Day)
+        } else if delta >= HOUR_ROLLUP_MIN_DELTA {
+            Some(RollupPeriod::Hour)
+        } else {
+            None
+        }
+    }
+    /// Applies `f` to the rollups of each window of [`Self::get_rolling_avg`],
+    /// with each window rounded out to whole periods
+    async fn map_rollup_windows<T: Clone + Send, F: Fn(&[Rollup]) -> Option<T> + Sync>(
+        &self,
+        period: RollupPeriod,
+        from: MinuteTimestamp,
+        to: MinuteTimestamp,
+        delta: u64,
+        f: F,
+    ) -> Result<Vec<Option<T>>> {
+        let rollups = self
+            .get_rollups(period, from.saturating_sub(delta), to.saturating_add(delta))
+            .await?;
+        let step = ((to - from) / 1000 + 1) as usize;
+        Ok((from..=to)
+            .step_by(step)
+            .collect::<Vec<_>>()
+            .into_par_iter()
+            .map(|min_ts| {
+                let start = rollups.partition_point(|a| {
+                    a._id.start + period.minutes() <= min_ts.saturating_sub(delta)
+                });
+                let end = rollups.partition_point(|a| a._id.start <= min_ts.saturating_add(delta));
+                vec![f(&rollups[start..end])]
+            })
+            .intersperse(vec![None; step - 1])
+            .flatten()
+            .collect())
+    }
     pub async fn get_hours(&self, from: HourTimestamp, to: HourTimestamp) -> Result<Vec<Hour>> {
         let a = self
             .0
@@ -76,36 +273,102 @@ impl STDatabase {
             .await
             .map(|a| a.map(Into::into))?)
     }
-    #[allow(clippy::cast_lossless)]
-    pub async fn get_minutes(
+    /// Every hour from the hour of `from` to the hour of `to`, sorted, with missing hours empty.
+    /// Hours are converted to one slot per minute, so that periods with different resolutions line up.
+    async fn get_all_hours(&self, from: MinuteTimestamp, to: MinuteTimestamp) -> Result<Vec<Hour>> {
+        self.get_all_hours_at(from, to, DEFAULT_RESOLUTION).await
+    }
+    /// [`Self::get_all_hours`], with every hour converted to `resolution` instead
+    async fn get_all_hours_at(
         &self,
         from: MinuteTimestamp,
         to: MinuteTimestamp,
-    ) -> Result<Vec<Option<Arc<AbsRecord>>>> {
+        resolution: u32,
+    ) -> Result<Vec<Hour>> {
         let from_h = (from / 60) as u32;
         let to_h = (to / 60) as u32;
-        let mut hours = self.get_hours(from_h, to_h).await?;
+        let mut hours = self
+            .get_hours(from_h, to_h)
+            .await?
+            .into_iter()
+            .map(|h| h.resample(resolution))
+            .collect::<Vec<_>>();
         for i in from_h..=to_h {
             if !hours.iter().any(|a| a._id == i) {
-                hours.push(Hour::new(i));
+                hours.push(Hour::with_resolution(i, resolution));
             }
         }
+        hours.sort_by_key(|a| a._id);
+        Ok(hours)
+    }
+    pub async fn get_minutes(
+        &self,
+        from: MinuteTimestamp,
+        to: MinuteTimestamp,
+    ) -> Result<Vec<Option<Arc<AbsRecord>>>> {
+        self.get_samples(from, to, DEFAULT_RESOLUTION).await
+    }
+    /// Records of the minutes from `from` to `to`, one every `resolution` seconds.
+    /// Hours stored at a finer resolution keep their extra samples, coarser ones are repeated.
+    #[allow(clippy::cast_lossless)]
+    pub async fn get_samples(
+        &self,
+        from: MinuteTimestamp,
+        to: MinuteTimestamp,
+        resolution: u32,
+    ) -> Result<Vec<Option<Arc<AbsRecord>>>> {
+        let per_minute = (60 / resolution) as usize;
+        let hours = self.get_all_hours_at(from, to, resolution).await?;
         let records = hours
             .iter()
-            .sorted_by_cached_key(|a| a._id)
             .flat_map(|h| &h.records)
-            .skip((from - from_h as u64 * 60) as usize)
-            .take((to - from + 1) as usize)
+            .skip((from % 60) as usize * per_minute)
+            .take((to - from + 1) as usize * per_minute)
             .cloned()
             .collect::<Vec<_>>();
         Ok(records)
     }
+    /// Runs of minutes without records from `from` to `to`, ignoring minutes that have not passed yet.
+    /// Minutes without a recorded reason are assumed to be from the tracker being offline.
+    pub async fn get_gaps(&self, from: MinuteTimestamp, to: MinuteTimestamp) -> Result<Vec<Gap>> {
+        let to = to.min(get_minute_timestamp(SystemTime::now()).saturating_sub(1));
+        if to < from {
+            return Ok(Vec::new());
+        }
+        let hours = self.get_all_hours(from, to).await?;
+        let mut out: Vec<Gap> = vec![];
+        for (min_ts, (record, reason)) in (from..=to).zip(
+            hours
+                .iter()
+                .flat_map(|h| h.records.iter().zip(h.gaps.iter().copied()))
+                .skip((from % 60) as usize),
+        ) {
+            if record.is_some() {
+                continue;
+            }
+            let reason = reason.unwrap_or(GapReason::TrackerOffline);
+            match out.last_mut() {
+                Some(gap) if gap.to + 1 == min_ts && gap.reason == reason => gap.to = min_ts,
+                _ => out.push(Gap {
+                    from: min_ts,
+                    to: min_ts,
+                    reason,
+                }),
+            }
+        }
+        Ok(out)
+    }
     pub async fn get_rolling_avg(
         &self,
         from: MinuteTimestamp,
         to: MinuteTimestamp,
         delta: u64,
     ) -> Result<Vec<Option<RollingAvgRecord>>> {
+        if let Some(period) = Self::rollup_period_for(delta) {
+            return self
+                .map_rollup_windows(period, from, to, delta, Rollup::mean_of)
+                .await;
+        }
         let mins = self
             .get_minutes(from.saturating_sub(delta), to.saturating_add(delta))
             .await?;
@@ -140,16 +403,60 @@ impl STDatabase {
     }
     #[tracing::instrument(skip(self))]
     pub async fn save_hour(&self, hour: Hour) -> Result<()> {
+        self.save_hour_def(&HourDef::from(hour)).await
+    }
+    /// Replaces the whole stored hour, as fields left out when serialising would otherwise keep their old values
+    pub async fn save_hour_def(&self, hour: &HourDef) -> Result<()> {
         info!("Saving hour");
-        let mut b = to_bson(&HourDef::from(hour.to_owned()))?;
-        b.as_document_mut().unwrap().remove("_id");
+        let start = u64::from(hour._id) * 60;
+        UNIQUE_CACHE.write().await.invalidate(start, start + 59);
         self.0
             .collection::<HourDef>("hours")
-            .update_one(doc! {"_id": hour._id}, doc! {"$set": b})
+            .replace_one(doc! {"_id": hour._id}, hour)
             .upsert(true)
             .await?;
         Ok(())
     }
+    /// Every stored hour from `from` to `to` inclusive, in order, as stored
+    pub async fn find_hour_defs(
+        &self,
+        from: Option<HourTimestamp>,
+        to: Option<HourTimestamp>,
+    ) -> Result<Cursor<HourDef>> {
+        Ok(self
+            .0
+            .collection::<HourDef>("hours")
+            .find(doc! {"_id": {
+                "$gte": from.unwrap_or(HourTimestamp::MIN),
+                "$lte": to.unwrap_or(HourTimestamp::MAX),
+            }})
+            .sort(doc! {"_id": 1})
+            .await?)
+    }
+    /// Calls `f` with every stored minute that has a record, in order, skipping hours with problems.
+    /// Returns the first and last hour that was read, if any.
+    pub async fn for_each_record<F: FnMut(MinuteTimestamp, &AbsRecord)>(
+        &self,
+        players: usize,
+        mut f: F,
+    ) -> Result<Option<(HourTimestamp, HourTimestamp)>> {
+        let mut range: Option<(HourTimestamp, HourTimestamp)> = None;
+        let mut hours = self.find_hour_defs(None, None).await?;
+        while let Some(hour) = hours.try_next().await? {
+            if !hour.problems(players).is_empty() {
+                warn!(hour = hour._id, "Skipping hour with problems, see `verify`");
+                continue;
+            }
+            range = Some(range.map_or((hour._id, hour._id), |(a, _)| (a, hour._id)));
+            let hour = Hour::from(hour).resample(DEFAULT_RESOLUTION);
+            for (min_ts, record) in (u64::from(hour._id) * 60..).zip(&hour.records) {
+                if let Some(record) = record {
+                    f(min_ts, record);
+                }
+            }
+        }
+        Ok(range)
+    }
     pub async fn get_player_join_times(
         &self,
         from: MinuteTimestamp,
@@ -157,30 +464,454 @@ impl STDatabase {
         player: usize,
     ) -> Result<Vec<(MinuteTimestamp, MinuteTimestamp)>> {
         let mins = self.get_minutes(from, to).await?;
-        let mut out = vec![];
-        let mut start: Option<MinuteTimestamp> = None;
-        let leave = |min: MinuteTimestamp,
-                     start: &mut Option<MinuteTimestamp>,
-                     out: &mut Vec<(MinuteTimestamp, MinuteTimestamp)>| {
-            if let Some(in_start) = *start {
-                out.push((in_start, min - 1));
-                *start = None;
+        Ok(intervals(from, &mins, |a| a.all.contains(&player)))
+    }
+    /// Intervals where both players were online at the same time
+    pub async fn get_shared_times(
+        &self,
+        from: MinuteTimestamp,
+        to: MinuteTimestamp,
+        player: usize,
+        other: usize,
+    ) -> Result<Vec<(MinuteTimestamp, MinuteTimestamp)>> {
+        let mins = self.get_minutes(from, to).await?;
+        Ok(intervals(from, &mins, |a| {
+            a.all.contains(&player) && a.all.contains(&other)
+        }))
+    }
+    /// Minutes every other player was online at the same time as `player`
+    pub async fn get_copresence(
+        &self,
+        from: MinuteTimestamp,
+        to: MinuteTimestamp,
+        player: usize,
+    ) -> Result<HashMap<usize, u64>> {
+        let mins = self.get_minutes(from, to).await?;
+        Ok(copresence(&mins, player))
+    }
+
+    #[tracing::instrument(skip(self))]
+    pub async fn save_outage(&self, outage: &Outage) -> Result<()> {
+        info!("Saving outage");
+        let mut b = to_bson(outage)?;
+        b.as_document_mut().unwrap().remove("_id");
+        self.0
+            .collection::<Outage>("outages")
+            .update_one(doc! {"_id": i64::try_from(outage.from)?}, doc! {"$set": b})
+            .upsert(true)
+            .await?;
+        Ok(())
+    }
+    /// Outages that overlap with the minutes from `from` to `to`
+    pub async fn get_outages(
+        &self,
+        from: MinuteTimestamp,
+        to: MinuteTimestamp,
+    ) -> Result<Vec<Outage>> {
+        let mut a = self
+            .0
+            .collection::<Outage>("outages")
+            .find(doc! {
+                "_id": {"$lte": i64::try_from(to)?},
+                "to": {"$gte": i64::try_from(from)?},
+            })
+            .await?
+            .collect::<Vec<_>>()
+            .await
+            .into_iter()
+            .collect::<mongodb::error::Result<Vec<_>>>()?;
+        a.sort_by_key(|a| a.from);
+        Ok(a)
+    }
+    /// Source availability for each UTC day from `from` to `to`
+    pub async fn get_uptime(
+        &self,
+        from: MinuteTimestamp,
+        to: MinuteTimestamp,
+    ) -> Result<Vec<DayUptime>> {
+        let hours = self.get_all_hours(from, to).await?;
+        let mut out: Vec<DayUptime> = vec![];
+        for (min_ts, (record, reason)) in (from..=to).zip(
+            hours
+                .iter()
+                .flat_map(|h| h.records.iter().zip(h.gaps.iter().copied()))
+                .skip((from % 60) as usize),
+        ) {
+            let day = min_ts / 60 / 24 * 60 * 24;
+            if out.last().is_none_or(|a| a.day != day) {
+                out.push(DayUptime {
+                    day,
+                    available: 0,
+                    observed: 0,
+                    uptime: None,
+                });
+            }
+            let uptime = out.last_mut().unwrap();
+            if record.is_some() || reason.is_some_and(GapReason::is_tracker_failure) {
+                uptime.available += 1;
+                uptime.observed += 1;
+            } else if reason.is_some_and(GapReason::is_source_failure) {
+                uptime.observed += 1;
             }
+        }
+        for uptime in &mut out {
+            uptime.uptime =
+                (uptime.observed != 0).then(|| uptime.available as f32 / uptime.observed as f32);
+        }
+        Ok(out)
+    }
+
+    #[tracing::instrument(skip_all)]
+    pub async fn save_rollup(&self, rollup: &Rollup) -> Result<()> {
+        debug!(?rollup._id, "Saving rollup");
+        let mut b = to_bson(rollup)?;
+        b.as_document_mut().unwrap().remove("_id");
+        self.0
+            .collection::<Rollup>("rollups")
+            .update_one(doc! {"_id": to_bson(&rollup._id)?}, doc! {"$set": b})
+            .upsert(true)
+            .await?;
+        Ok(())
+    }
+    /// Saves the rollup of an hour that was just written to, and updates the rollup of its day.
+    /// Only the hours before it in the day are read, as later hours have no records yet.
+    async fn update_rollups(&self, hour_rollup: Rollup) -> Result<()> {
+        self.save_rollup(&hour_rollup).await?;
+        let day = RollupPeriod::Day.start_of(hour_rollup._id.start);
+        let mut hour_rollups = if hour_rollup._id.start == day {
+            vec![]
+        } else {
+            self.get_hour_rollups(day, hour_rollup._id.start - 1)
+                .await?
         };
-        for (min, record) in (from..=to).zip_eq(mins) {
+        hour_rollups.push(hour_rollup);
+        self.save_rollup(&Rollup::combine(
+            RollupId {
+                period: RollupPeriod::Day,
+                start: day,
+            },
+            &hour_rollups,
+        ))
+        .await?;
+        Ok(())
+    }
+    /// Deletes the rollups of every hour and day that overlaps with the minutes from `from` to `to`,
+    /// so that they are computed again from the stored hours
+    pub async fn delete_rollups(&self, from: MinuteTimestamp, to: MinuteTimestamp) -> Result<()> {
+        let result = self
+            .0
+            .collection::<Rollup>("rollups")
+            .delete_many(doc! {
+                "_id.start": {
+                    "$gte": i64::try_from(RollupPeriod::Day.start_of(from))?,
+                    "$lte": i64::try_from(to)?
+                }
+            })
+            .await?;
+        info!(count = result.deleted_count, "Deleted rollups");
+        Ok(())
+    }
+    async fn find_rollups(
+        &self,
+        period: RollupPeriod,
+        from: MinuteTimestamp,
+        to: MinuteTimestamp,
+    ) -> Result<Vec<Rollup>> {
+        Ok(self
+            .0
+            .collection::<Rollup>("rollups")
+            .find(doc! {
+                "_id.period": to_bson(&period)?,
+                "_id.start": {
+                    "$gte": i64::try_from(period.start_of(from))?,
+                    "$lte": i64::try_from(to)?
+                }
+            })
+            .await?
+            .collect::<Vec<_>>()
+            .await
+            .into_iter()
+            .filter_ok(|a| !a.is_outdated())
+            .collect::<mongodb::error::Result<Vec<_>>>()?)
+    }
+    /// Starts of the periods from `from` to `to` that are not in `rollups`
+    fn missing_rollups(
+        period: RollupPeriod,
+        from: MinuteTimestamp,
+        to: MinuteTimestamp,
+        rollups: &[Rollup],
+    ) -> Vec<MinuteTimestamp> {
+        let found = rollups.iter().map(|a| a._id.start).collect::<HashSet<_>>();
+        (period.start_of(from)..=to)
+            .step_by(period.minutes() as usize)
+            .filter(|a| !found.contains(a))
+            .collect()
+    }
+    /// Saves rollups that were computed on the fly, if their period is over
+    async fn save_computed_rollups(&self, rollups: &[Rollup]) -> Result<()> {
+        let now = get_minute_timestamp(SystemTime::now());
+        for rollup in rollups {
+            if rollup._id.start + rollup._id.period.minutes() <= now {
+                self.save_rollup(rollup).await?;
+            }
+        }
+        Ok(())
+    }
+    async fn get_hour_rollups(
+        &self,
+        from: MinuteTimestamp,
+        to: MinuteTimestamp,
+    ) -> Result<Vec<Rollup>> {
+        let mut rollups = self.find_rollups(RollupPeriod::Hour, from, to).await?;
+        let missing = Self::missing_rollups(RollupPeriod::Hour, from, to, &rollups);
+        if !missing.is_empty() {
+            debug!(count = missing.len(), "Computing missing hour rollups");
+            let hours = self
+                .0
+                .collection::<HourDef>("hours")
+                .find(doc! {
+                    "_id": {"$in": missing.iter().map(|a| (a / 60) as HourTimestamp).collect::<Vec<_>>()}
+                })
+                .await?
+                .collect::<Vec<_>>()
+                .await
+                .into_iter()
+                .map_ok(|a| Rollup::from_hour(&a.into()))
+                .collect::<mongodb::error::Result<Vec<_>>>()?;
+            let computed = missing
+                .into_iter()
+                .map(|start| {
+                    hours
+                        .iter()
+                        .find(|a| a._id.start == start)
+                        .cloned()
+                        .unwrap_or_else(|| {
+                            Rollup::empty(RollupId {
+                                period: RollupPeriod::Hour,
+                                start,
+                            })
+                        })
+                })
+                .collect::<Vec<_>>();
+            self.save_computed_rollups(&computed).await?;
+            rollups.extend(computed);
+        }
+        rollups.sort_by_key(|a| a._id.start);
+        Ok(rollups)
+    }
+    async fn get_day_rollups(
+        &self,
+        from: MinuteTimestamp,
+        to: MinuteTimestamp,
+    ) -> Result<Vec<Rollup>> {
+        let mut rollups = self.find_rollups(RollupPeriod::Day, from, to).await?;
+        let missing = Self::missing_rollups(RollupPeriod::Day, from, to, &rollups);
+        if let (Some(first), Some(last)) = (missing.first(), missing.last()) {
+            debug!(count = missing.len(), "Computing missing day rollups");
+            let hour_rollups = self
+                .get_hour_rollups(*first, last + RollupPeriod::Day.minutes() - 1)
+                .await?;
+            let computed = missing
+                .iter()
+                .map(|start| {
+                    Rollup::combine(
+                        RollupId {
+                            period: RollupPeriod::Day,
+                            start: *start,
+                        },
+                        &hour_rollups
+                            .iter()
+                            .filter(|a| RollupPeriod::Day.start_of(a._id.start) == *start)
+                            .cloned()
+                            .collect::<Vec<_>>(),
+                    )
+                })
+                .collect::<Vec<_>>();
+            self.save_computed_rollups(&computed).await?;
+            rollups.extend(computed);
+        }
+        rollups.sort_by_key(|a| a._id.start);
+        Ok(rollups)
+    }
+    /// Rollups of every period that overlaps with the minutes from `from` to `to`, sorted.
+    /// Rollups that are missing, eg for hours written before rollups existed, are computed and saved.
+    pub async fn get_rollups(
+        &self,
+        period: RollupPeriod,
+        from: MinuteTimestamp,
+        to: MinuteTimestamp,
+    ) -> Result<Vec<Rollup>> {
+        match period {
+            RollupPeriod::Hour => self.get_hour_rollups(from, to).await,
+            RollupPeriod::Day => self.get_day_rollups(from, to).await,
+        }
+    }
+    /// Every player online during each of the periods starting at `starts`
+    pub async fn get_active_players(
+        &self,
+        period: Period,
+        starts: &[MinuteTimestamp],
+    ) -> Result<Vec<ActivePlayers>> {
+        let (Some(first), Some(last)) = (starts.iter().min(), starts.iter().max()) else {
+            return Ok(Vec::new());
+        };
+        let days = self
+            .get_rollups(RollupPeriod::Day, *first, period.next(*last) - 1)
+            .await?;
+        Ok(starts
+            .iter()
+            .map(|start| {
+                let end = period.next(*start);
+                let mut active = ActivePlayers {
+                    start: *start,
+                    ..ActivePlayers::default()
+                };
+                for day in days
+                    .iter()
+                    .filter(|a| a._id.start >= *start && a._id.start < end)
+                {
+                    active.all.extend(&day.all.players);
+                    for (cat, stats) in &day.categories {
+                        active
+                            .categories
+                            .entry(cat.to_owned())
+                            .or_default()
+                            .extend(&stats.players);
+                    }
+                }
+                active
+            })
+            .collect())
+    }
+    /// Number of distinct players online in each period that overlaps with the minutes from `from` to `to`
+    pub async fn get_unique_counts(
+        &self,
+        period: Period,
+        from: MinuteTimestamp,
+        to: MinuteTimestamp,
+    ) -> Result<Vec<UniqueCount>> {
+        let starts = period.starts(from, to);
+        let mut out = vec![];
+        let mut uncached = vec![];
+        {
+            let cache = UNIQUE_CACHE.read().await;
+            for start in starts {
+                if let Some(count) = cache.get(period, start) {
+                    out.push(count.to_owned());
+                } else {
+                    uncached.push(start);
+                }
+            }
+        }
+        if uncached.is_empty() {
+            return Ok(out);
+        }
+        debug!(count = uncached.len(), "Computing unique counts");
+        let active = self.get_active_players(period, &uncached).await?;
+        let now = get_minute_timestamp(SystemTime::now());
+        let mut cache = UNIQUE_CACHE.write().await;
+        for active in active {
+            let count = UniqueCount {
+                start: active.start,
+                all: active.all.len(),
+                categories: active
+                    .categories
+                    .into_iter()
+                    .map(|(cat, players)| (cat, players.len()))
+                    .collect(),
+            };
+            if period.next(active.start) <= now {
+                cache.insert(period, count.to_owned());
+            }
+            out.push(count);
+        }
+        out.sort_by_key(|a| a.start);
+        Ok(out)
+    }
+    /// Playtime of every player online from `from` to `to`, keyed by name map index.
+    /// If `category` is given, only minutes where the player was online and in that category are counted.
+    pub async fn get_playtimes(
+        &self,
+        from: MinuteTimestamp,
+        to: MinuteTimestamp,
+        category: Option<&str>,
+    ) -> Result<HashMap<usize, Playtime>> {
+        let mins = self.get_minutes(from, to).await?;
+        let empty_hash_set = HashSet::new();
+        let mut out: HashMap<usize, Playtime> = HashMap::new();
+        let mut prev: &HashSet<usize> = &empty_hash_set;
+        for record in &mins {
             let Some(record) = record else {
-                leave(min, &mut start, &mut out);
+                prev = &empty_hash_set;
                 continue;
             };
-            if record.all.contains(&player) {
-                if start.is_none() {
-                    start = Some(min);
-                };
-            } else {
-                leave(min, &mut start, &mut out);
+            let players = match category {
+                Some(cat) => record.categories.get(cat).unwrap_or(&empty_hash_set),
+                None => &record.all,
+            };
+            for player in players {
+                let playtime = out.entry(*player).or_default();
+                playtime.minutes += 1;
+                if !prev.contains(player) {
+                    playtime.sessions += 1;
+                }
             }
+            prev = players;
         }
-        leave(to + 1, &mut start, &mut out);
         Ok(out)
     }
+
+    #[tracing::instrument(skip_all)]
+    pub async fn save_peaks(&self, peaks: &Peaks) -> Result<()> {
+        debug!(?peaks._id, "Saving peaks");
+        let mut b = to_bson(peaks)?;
+        b.as_document_mut().unwrap().remove("_id");
+        self.0
+            .collection::<Peaks>("peaks")
+            .update_one(doc! {"_id": to_bson(&peaks._id)?}, doc! {"$set": b})
+            .upsert(true)
+            .await?;
+        Ok(())
+    }
+    /// Whether any peaks are stored
+    pub async fn has_peaks(&self) -> Result<bool> {
+        Ok(self
+            .0
+            .collection::<Peaks>("peaks")
+            .find_one(doc! {})
+            .await?
+            .is_some())
+    }
+    pub async fn get_peaks(&self, id: PeakId) -> Result<Option<Peaks>> {
+        Ok(self
+            .0
+            .collection::<Peaks>("peaks")
+            .find_one(doc! {"_id": to_bson(&id)?})
+            .await?)
+    }
+    /// Peaks of every period starting from `from` to `to`, sorted
+    pub async fn find_peaks(
+        &self,
+        period: PeakPeriod,
+        from: MinuteTimestamp,
+        to: MinuteTimestamp,
+    ) -> Result<Vec<Peaks>> {
+        let mut a = self
+            .0
+            .collection::<Peaks>("peaks")
+            .find(doc! {
+                "_id.period": to_bson(&period)?,
+                "_id.start": {
+                    "$gte": i64::try_from(period.start_of(from))?,
+                    "$lte": i64::try_from(to)?
+                }
+            })
+            .await?
+            .collect::<Vec<_>>()
+            .await
+            .into_iter()
+            .collect::<mongodb::error::Result<Vec<_>>>()?;
+        a.sort_by_key(|a| a._id.start);
+        Ok(a)
+    }
 }
@@ -0,0 +1,64 @@
+use std::collections::HashMap;
+
+use serde::{Deserialize, Serialize};
+use smol_str::SmolStr;
+use uuid::Uuid;
+
+use crate::{
+    hour::GapReason,
+    peaks::BrokenPeak,
+    utils::{Category, SecondTimestamp},
+};
+
+/// Capacity of the event channel, slower subscribers miss older events
+pub const EVENT_CAPACITY: usize = 1024;
+
+/// Something that happened while tracking, sent to every subscriber of [`crate::tracker::StatusTracker::events`]
+#[derive(Deserialize, Serialize, Debug, Clone, PartialEq, Eq)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum TrackerEvent {
+    PeakBroken(BrokenPeak),
+    PlayerJoined(PlayerEvent),
+    PlayerLeft(PlayerEvent),
+    CountChanged {
+        time: SecondTimestamp,
+        all: usize,
+        categories: HashMap<Category, usize>,
+    },
+    /// Every member of a category went offline
+    CoverageLost {
+        time: SecondTimestamp,
+        category: Category,
+    },
+    /// Sent once for every interval where polling failed, not for retries.
+    /// This includes failures after the source was polled, eg looking up UUIDs or writing to the database.
+    PollFailed {
+        time: SecondTimestamp,
+        /// Number of failed polls in a row, including this one
+        failed_polls: u32,
+        reason: GapReason,
+    },
+}
+impl TrackerEvent {
+    #[must_use]
+    pub const fn name(&self) -> &'static str {
+        match self {
+            Self::PeakBroken(_) => "peak_broken",
+            Self::PlayerJoined(_) => "player_joined",
+            Self::PlayerLeft(_) => "player_left",
+            Self::CountChanged { .. } => "count_changed",
+            Self::CoverageLost { .. } => "coverage_lost",
+            Self::PollFailed { .. } => "poll_failed",
+        }
+    }
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone, PartialEq, Eq)]
+pub struct PlayerEvent {
+    /// Second timestamp of the poll the change was seen in
+    pub time: SecondTimestamp,
+    pub uuid: Uuid,
+    pub name: Option<SmolStr>,
+    /// Categories the player joined or left with
+    pub categories: Vec<Category>,
+}
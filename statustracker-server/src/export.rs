@@ -0,0 +1,561 @@
+use std::{
+    collections::{BTreeMap, HashMap, HashSet},
+    fs::File,
+    path::PathBuf,
+    sync::Arc,
+};
+
+use arrow_array::{
+    ArrayRef, RecordBatch, StringArray, TimestampSecondArray, UInt32Array, UInt64Array,
+};
+use arrow_schema::{DataType, Field, Schema, SchemaRef, TimeUnit};
+use chrono::{DateTime, Datelike, Months, NaiveDate, NaiveTime, SecondsFormat};
+use clap::ValueEnum;
+use color_eyre::eyre::{eyre, Result};
+use parquet::{arrow::ArrowWriter, basic::Compression, file::properties::WriterProperties};
+use rocket::{http::ContentType, FromFormField};
+use serde::Serialize;
+use smol_str::SmolStr;
+use tracing::info;
+use uuid::Uuid;
+
+use crate::{
+    database::STDatabase,
+    hour::AbsRecord,
+    name_to_uuid::{cached_name, try_uuid_to_name},
+    tracker::NameMapWrapper,
+    utils::{Category, MinuteTimestamp, SecondTimestamp},
+};
+
+/// Number of minutes read from the database at once
+pub const CHUNK_MINUTES: u64 = 60 * 24;
+
+/// Checks that rows can be exported every `resolution` seconds
+pub fn check_resolution(resolution: u32) -> Result<()> {
+    if resolution == 0 || !60u32.is_multiple_of(resolution) {
+        return Err(eyre!("Resolution must divide evenly into a minute"));
+    }
+    Ok(())
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum, FromFormField)]
+pub enum ExportFormat {
+    Csv,
+    Ndjson,
+}
+impl ExportFormat {
+    #[must_use]
+    pub fn content_type(self) -> ContentType {
+        match self {
+            Self::Csv => ContentType::CSV,
+            Self::Ndjson => ContentType::new("application", "x-ndjson"),
+        }
+    }
+}
+
+/// What to list the online players by, if at all
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, ValueEnum, FromFormField)]
+pub enum PlayerColumn {
+    #[default]
+    None,
+    Uuids,
+    Names,
+}
+
+#[derive(Serialize, Debug)]
+struct MinuteRow<'a> {
+    minute: MinuteTimestamp,
+    /// Start of the sample, which is not the start of the minute for resolutions under a minute
+    time: String,
+    /// `None` if the sample has no record
+    all: Option<usize>,
+    categories: BTreeMap<&'a str, usize>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    players: Option<Vec<String>>,
+}
+
+/// Writes minutes as CSV or NDJSON rows, one chunk at a time
+pub struct MinuteExport {
+    pub format: ExportFormat,
+    pub players: PlayerColumn,
+    /// Categories to count, in column order
+    pub categories: Vec<Category>,
+    /// Seconds between rows, only under a minute for hours sampled that often
+    pub resolution: u32,
+    /// Whether names missing from the cache are looked up from the API, instead of listed by UUID
+    pub lookup_names: bool,
+    names: HashMap<Uuid, String>,
+}
+
+fn csv_field(field: &str) -> String {
+    if field.contains([',', '"', '\n', '\r']) {
+        format!("\"{}\"", field.replace('"', "\"\""))
+    } else {
+        field.to_owned()
+    }
+}
+
+impl MinuteExport {
+    #[must_use]
+    pub fn new(
+        format: ExportFormat,
+        players: PlayerColumn,
+        mut categories: Vec<Category>,
+        resolution: u32,
+    ) -> Self {
+        categories.sort();
+        Self {
+            format,
+            players,
+            categories,
+            resolution,
+            lookup_names: false,
+            names: HashMap::new(),
+        }
+    }
+
+    /// The starts and ends of the chunks to export the minutes from `from` to `to` in
+    pub fn chunks(
+        from: MinuteTimestamp,
+        to: MinuteTimestamp,
+    ) -> impl Iterator<Item = (MinuteTimestamp, MinuteTimestamp)> {
+        (from..=to)
+            .step_by(CHUNK_MINUTES as usize)
+            .map(move |start| (start, (start + CHUNK_MINUTES - 1).min(to)))
+    }
+
+    /// The first line of the output, if there is one
+    #[must_use]
+    pub fn header(&self) -> Option<String> {
+        (self.format == ExportFormat::Csv).then(|| {
+            let mut columns = vec!["minute".to_owned(), "time".into(), "all".into()];
+            columns.extend(self.categories.iter().map(|a| csv_field(a)));
+            if self.players != PlayerColumn::None {
+                columns.push("players".into());
+            }
+            let mut line = columns.join(",");
+            line.push('\n');
+            line
+        })
+    }
+
+    async fn name(&mut self, uuid: Uuid) -> String {
+        if let Some(name) = self.names.get(&uuid) {
+            return name.to_owned();
+        }
+        let name = match cached_name(uuid).await {
+            Some(name) => Some(name),
+            None if self.lookup_names => try_uuid_to_name(uuid).await,
+            None => None,
+        }
+        .map_or_else(|| uuid.to_string(), SmolStr::into);
+        self.names.insert(uuid, name.to_owned());
+        name
+    }
+
+    /// Rows of the minutes from `from` to `to`
+    pub async fn rows(
+        &mut self,
+        database: &STDatabase,
+        name_map: &NameMapWrapper,
+        from: MinuteTimestamp,
+        to: MinuteTimestamp,
+    ) -> Result<String> {
+        let samples = database.get_samples(from, to, self.resolution).await?;
+        let mut out = String::new();
+        for (ts, record) in (from * 60..)
+            .step_by(self.resolution as usize)
+            .zip(&samples)
+        {
+            let players = match (self.players, record) {
+                (PlayerColumn::None, _) => None,
+                (_, None) => Some(vec![]),
+                (players, Some(record)) => {
+                    let mut list = vec![];
+                    for id in &record.all {
+                        let Some(uuid) = name_map.data.get(*id).map(|a| Uuid::from_bytes(*a))
+                        else {
+                            continue;
+                        };
+                        list.push(if players == PlayerColumn::Names {
+                            self.name(uuid).await
+                        } else {
+                            uuid.to_string()
+                        });
+                    }
+                    list.sort();
+                    Some(list)
+                }
+            };
+            out.push_str(&self.row(ts, record.as_deref(), players)?);
+        }
+        Ok(out)
+    }
+
+    fn row(
+        &self,
+        ts: SecondTimestamp,
+        record: Option<&AbsRecord>,
+        players: Option<Vec<String>>,
+    ) -> Result<String> {
+        let row = MinuteRow {
+            minute: ts / 60,
+            time: DateTime::from_timestamp(i64::try_from(ts)?, 0)
+                .unwrap_or_default()
+                .to_rfc3339_opts(SecondsFormat::Secs, true),
+            all: record.map(|a| a.all.len()),
+            categories: record
+                .map(|record| {
+                    self.categories
+                        .iter()
+                        .map(|cat| {
+                            (
+                                cat.as_str(),
+                                record.categories.get(cat).map_or(0, HashSet::len),
+                            )
+                        })
+                        .collect()
+                })
+                .unwrap_or_default(),
+            players,
+        };
+        let mut line = match self.format {
+            ExportFormat::Ndjson => serde_json::to_string(&row)?,
+            ExportFormat::Csv => {
+                let mut columns = vec![
+                    row.minute.to_string(),
+                    row.time,
+                    row.all.map(|a| a.to_string()).unwrap_or_default(),
+                ];
+                columns.extend(self.categories.iter().map(|cat| {
+                    row.categories
+                        .get(cat.as_str())
+                        .map(ToString::to_string)
+                        .unwrap_or_default()
+                }));
+                if let Some(players) = row.players {
+                    columns.push(csv_field(&players.join(";")));
+                }
+                columns.join(",")
+            }
+        };
+        line.push('\n');
+        Ok(line)
+    }
+}
+
+/// The start of the month `min_ts` is in, and the start of the next month
+fn month_of(min_ts: MinuteTimestamp) -> Result<(NaiveDate, MinuteTimestamp, MinuteTimestamp)> {
+    let start = DateTime::from_timestamp(i64::try_from(min_ts * 60)?, 0)
+        .ok_or_else(|| eyre!("Timestamp out of range"))?
+        .date_naive()
+        .with_day(1)
+        .ok_or_else(|| eyre!("Invalid date"))?;
+    let next = start + Months::new(1);
+    let to_min =
+        |date: NaiveDate| u64::try_from(date.and_time(NaiveTime::MIN).and_utc().timestamp() / 60);
+    Ok((start, to_min(start)?, to_min(next)?))
+}
+
+/// Writes minutes as Parquet files of counts and of who was online, partitioned by month
+pub struct ParquetExport {
+    /// Categories to count, in column order
+    pub categories: Vec<Category>,
+    pub dir: PathBuf,
+}
+
+impl ParquetExport {
+    #[must_use]
+    pub fn new(mut categories: Vec<Category>, dir: PathBuf) -> Self {
+        categories.sort();
+        Self { categories, dir }
+    }
+
+    /// The months to export the minutes from `from` to `to` in, with the first and last minute of each
+    pub fn months(
+        from: MinuteTimestamp,
+        to: MinuteTimestamp,
+    ) -> Result<Vec<(NaiveDate, MinuteTimestamp, MinuteTimestamp)>> {
+        let mut months = vec![];
+        let mut start = from;
+        while start <= to {
+            let (month, _, next) = month_of(start)?;
+            months.push((month, start, (next - 1).min(to)));
+            start = next;
+        }
+        Ok(months)
+    }
+
+    fn counts_schema(&self) -> SchemaRef {
+        let mut fields = vec![
+            Field::new("minute", DataType::UInt64, false),
+            Field::new(
+                "time",
+                DataType::Timestamp(TimeUnit::Second, Some("UTC".into())),
+                false,
+            ),
+            Field::new("all", DataType::UInt32, true),
+        ];
+        fields.extend(
+            self.categories
+                .iter()
+                .map(|cat| Field::new(cat.as_str(), DataType::UInt32, true)),
+        );
+        Arc::new(Schema::new(fields))
+    }
+
+    fn presence_schema() -> SchemaRef {
+        Arc::new(Schema::new(vec![
+            Field::new("minute", DataType::UInt64, false),
+            Field::new("player_index", DataType::UInt32, false),
+        ]))
+    }
+
+    fn writer(
+        &self,
+        table: &str,
+        month: NaiveDate,
+        schema: SchemaRef,
+    ) -> Result<ArrowWriter<File>> {
+        let dir = self
+            .dir
+            .join(table)
+            .join(format!("month={}", month.format("%Y-%m")));
+        std::fs::create_dir_all(&dir)?;
+        let props = WriterProperties::builder()
+            .set_compression(Compression::SNAPPY)
+            .build();
+        Ok(ArrowWriter::try_new(
+            File::create(dir.join("data.parquet"))?,
+            schema,
+            Some(props),
+        )?)
+    }
+
+    /// The counts and the `(minute, player_index)` pairs of the minutes starting at `from`
+    fn batches(
+        &self,
+        from: MinuteTimestamp,
+        records: &[Option<Arc<AbsRecord>>],
+    ) -> Result<(RecordBatch, RecordBatch)> {
+        let count = |a: &HashSet<usize>| u32::try_from(a.len());
+        let minutes = (from..).take(records.len()).collect::<Vec<_>>();
+        let mut columns: Vec<ArrayRef> = vec![
+            Arc::new(UInt64Array::from(minutes.clone())),
+            Arc::new(
+                TimestampSecondArray::from(
+                    minutes
+                        .iter()
+                        .map(|a| i64::try_from(a * 60))
+                        .collect::<Result<Vec<_>, _>>()?,
+                )
+                .with_timezone("UTC"),
+            ),
+            Arc::new(UInt32Array::from(
+                records
+                    .iter()
+                    .map(|a| a.as_ref().map(|a| count(&a.all)).transpose())
+                    .collect::<Result<Vec<_>, _>>()?,
+            )),
+        ];
+        for cat in &self.categories {
+            columns.push(Arc::new(UInt32Array::from(
+                records
+                    .iter()
+                    .map(|a| {
+                        a.as_ref()
+                            .map(|a| a.categories.get(cat).map_or(Ok(0), count))
+                            .transpose()
+                    })
+                    .collect::<Result<Vec<_>, _>>()?,
+            )));
+        }
+
+        let mut presence_minutes = vec![];
+        let mut players = vec![];
+        for (min_ts, record) in minutes.iter().zip(records) {
+            let Some(record) = record else { continue };
+            let mut all = record.all.iter().copied().collect::<Vec<_>>();
+            all.sort_unstable();
+            for player in all {
+                presence_minutes.push(*min_ts);
+                players.push(u32::try_from(player)?);
+            }
+        }
+        Ok((
+            RecordBatch::try_new(self.counts_schema(), columns)?,
+            RecordBatch::try_new(
+                Self::presence_schema(),
+                vec![
+                    Arc::new(UInt64Array::from(presence_minutes)),
+                    Arc::new(UInt32Array::from(players)),
+                ],
+            )?,
+        ))
+    }
+
+    /// Write the minutes from `from` to `to`, and `players.parquet` mapping player indexes to UUIDs
+    pub async fn write(
+        &self,
+        database: &STDatabase,
+        name_map: &NameMapWrapper,
+        from: MinuteTimestamp,
+        to: MinuteTimestamp,
+    ) -> Result<()> {
+        std::fs::create_dir_all(&self.dir)?;
+        let players_schema = Arc::new(Schema::new(vec![
+            Field::new("player_index", DataType::UInt32, false),
+            Field::new("uuid", DataType::Utf8, false),
+        ]));
+        let players = RecordBatch::try_new(
+            Arc::clone(&players_schema),
+            vec![
+                Arc::new(UInt32Array::from(
+                    (0..u32::try_from(name_map.data.len())?).collect::<Vec<_>>(),
+                )),
+                Arc::new(StringArray::from(
+                    name_map
+                        .data
+                        .iter()
+                        .map(|a| Uuid::from_bytes(*a).to_string())
+                        .collect::<Vec<_>>(),
+                )),
+            ],
+        )?;
+        let mut writer = ArrowWriter::try_new(
+            File::create(self.dir.join("players.parquet"))?,
+            players_schema,
+            None,
+        )?;
+        writer.write(&players)?;
+        writer.close()?;
+
+        for (month, from, to) in Self::months(from, to)? {
+            let mut counts = self.writer("counts", month, self.counts_schema())?;
+            let mut presence = self.writer("presence", month, Self::presence_schema())?;
+            for (from, to) in MinuteExport::chunks(from, to) {
+                let records = database.get_minutes(from, to).await?;
+                let (count_batch, presence_batch) = self.batches(from, &records)?;
+                counts.write(&count_batch)?;
+                presence.write(&presence_batch)?;
+            }
+            counts.close()?;
+            presence.close()?;
+            info!(month = %month.format("%Y-%m"), "Exported month");
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        collections::{HashMap, HashSet},
+        sync::Arc,
+    };
+
+    use arrow_array::{cast::AsArray, types::UInt32Type};
+    use chrono::NaiveDate;
+
+    use crate::{
+        export::{ExportFormat, MinuteExport, ParquetExport, PlayerColumn},
+        hour::AbsRecord,
+    };
+
+    #[test]
+    pub fn export_rows() {
+        let record = AbsRecord {
+            all: HashSet::from([0, 1]),
+            categories: HashMap::from([("staff".into(), HashSet::from([1]))]),
+        };
+        let players = Some(vec!["Alex".to_owned(), "Steve, Jr".to_owned()]);
+        let csv = MinuteExport::new(
+            ExportFormat::Csv,
+            PlayerColumn::Names,
+            vec!["staff".into(), "builder".into()],
+            60,
+        );
+        assert_eq!(
+            csv.header().unwrap(),
+            "minute,time,all,builder,staff,players\n"
+        );
+        assert_eq!(
+            csv.row(60, Some(&record), players.clone()).unwrap(),
+            "1,1970-01-01T00:01:00Z,2,0,1,\"Alex;Steve, Jr\"\n"
+        );
+        assert_eq!(
+            csv.row(120, None, Some(vec![])).unwrap(),
+            "2,1970-01-01T00:02:00Z,,,,\n"
+        );
+
+        let ndjson = MinuteExport::new(
+            ExportFormat::Ndjson,
+            PlayerColumn::None,
+            vec!["staff".into()],
+            15,
+        );
+        assert_eq!(ndjson.header(), None);
+        assert_eq!(
+            ndjson.row(75, Some(&record), None).unwrap(),
+            "{\"minute\":1,\"time\":\"1970-01-01T00:01:15Z\",\"all\":2,\"categories\":{\"staff\":1}}\n"
+        );
+        assert_eq!(
+            MinuteExport::chunks(0, 60 * 24 * 2).collect::<Vec<_>>(),
+            vec![(0, 1439), (1440, 2879), (2880, 2880)]
+        );
+    }
+
+    #[test]
+    pub fn parquet_batches() {
+        // 2024-01-31 23:59 UTC
+        let from = 28_445_759;
+        assert_eq!(
+            ParquetExport::months(from, from + 2).unwrap(),
+            vec![
+                (NaiveDate::from_ymd_opt(2024, 1, 1).unwrap(), from, from),
+                (
+                    NaiveDate::from_ymd_opt(2024, 2, 1).unwrap(),
+                    from + 1,
+                    from + 2
+                ),
+            ]
+        );
+
+        let export = ParquetExport::new(vec!["staff".into()], "export".into());
+        let record = AbsRecord {
+            all: HashSet::from([3, 1]),
+            categories: HashMap::from([("staff".into(), HashSet::from([1]))]),
+        };
+        let (counts, presence) = export
+            .batches(from, &[Some(Arc::new(record)), None])
+            .unwrap();
+        assert_eq!(counts.num_rows(), 2);
+        assert_eq!(
+            counts
+                .column_by_name("all")
+                .unwrap()
+                .as_primitive::<UInt32Type>()
+                .iter()
+                .collect::<Vec<_>>(),
+            vec![Some(2), None]
+        );
+        assert_eq!(
+            counts
+                .column_by_name("staff")
+                .unwrap()
+                .as_primitive::<UInt32Type>()
+                .iter()
+                .collect::<Vec<_>>(),
+            vec![Some(1), None]
+        );
+        assert_eq!(
+            presence
+                .column_by_name("player_index")
+                .unwrap()
+                .as_primitive::<UInt32Type>()
+                .values()
+                .to_vec(),
+            vec![1, 3]
+        );
+    }
+}
@@ -0,0 +1,160 @@
+use std::{
+    sync::atomic::{AtomicBool, AtomicU32, AtomicU64, Ordering},
+    time::{Duration, SystemTime},
+};
+
+use mongodb::{bson::doc, Database};
+use serde::Serialize;
+
+use crate::{
+    hour::DEFAULT_RESOLUTION,
+    utils::{get_second_timestamp, SecondTimestamp},
+};
+
+// These are kept outside of the tracker so that checks still work while a poll holds its lock
+static LAST_POLL: AtomicU64 = AtomicU64::new(0);
+static LAST_RECORD: AtomicU64 = AtomicU64::new(0);
+static LAST_SOURCE_FAILURE: AtomicU64 = AtomicU64::new(0);
+static SAMPLE_INTERVAL: AtomicU32 = AtomicU32::new(DEFAULT_RESOLUTION);
+static MAINTENANCE: AtomicBool = AtomicBool::new(false);
+
+/// Polls and records are stale after this many sample intervals
+const STALE_INTERVALS: u64 = 3;
+/// Polls and records are never stale before this many seconds
+const MIN_STALE_SECS: u64 = 180;
+const PING_TIMEOUT: Duration = Duration::from_secs(2);
+
+/// Called at the start of every poll
+pub fn start_poll(sample_interval: u32, maintenance: bool) {
+    SAMPLE_INTERVAL.store(sample_interval, Ordering::Relaxed);
+    MAINTENANCE.store(maintenance, Ordering::Relaxed);
+}
+/// Called when a poll finishes, successfully or not
+pub fn finish_poll(ts: SecondTimestamp) {
+    LAST_POLL.store(ts, Ordering::Relaxed);
+}
+pub fn record_written(ts: SecondTimestamp) {
+    LAST_RECORD.store(ts, Ordering::Relaxed);
+}
+pub fn source_failed(ts: SecondTimestamp) {
+    LAST_SOURCE_FAILURE.store(ts, Ordering::Relaxed);
+}
+
+fn load(a: &AtomicU64) -> Option<SecondTimestamp> {
+    match a.load(Ordering::Relaxed) {
+        0 => None,
+        a => Some(a),
+    }
+}
+
+/// Whether nothing has happened for too long since `last`, or since `started` if it never happened
+#[must_use]
+pub fn is_stale(
+    last: Option<SecondTimestamp>,
+    started: SecondTimestamp,
+    now: SecondTimestamp,
+    sample_interval: u32,
+) -> bool {
+    let threshold = (u64::from(sample_interval) * STALE_INTERVALS).max(MIN_STALE_SECS);
+    now.saturating_sub(last.unwrap_or(started).max(started)) > threshold
+}
+
+#[derive(Serialize, Debug, Clone, PartialEq, Eq)]
+pub struct HealthReport {
+    pub ok: bool,
+    /// `None` if the database was not checked
+    pub database: Option<bool>,
+    pub polling: bool,
+    pub last_poll: Option<SecondTimestamp>,
+    pub last_record: Option<SecondTimestamp>,
+    pub seconds_since_last_record: Option<u64>,
+    /// Whether the latest poll could read the source, `None` if there has not been one
+    pub source_reachable: Option<bool>,
+    pub maintenance: bool,
+}
+
+/// Managed by Rocket for the health routes
+pub struct Health {
+    pub database: Database,
+    /// `true` if the tracker does not poll, so polls are never stale
+    pub no_write: bool,
+    pub started: SecondTimestamp,
+}
+impl Health {
+    #[must_use]
+    pub fn new(database: Database, no_write: bool) -> Self {
+        Self {
+            database,
+            no_write,
+            started: get_second_timestamp(SystemTime::now()),
+        }
+    }
+
+    async fn ping(&self) -> bool {
+        tokio::time::timeout(PING_TIMEOUT, self.database.run_command(doc! {"ping": 1}))
+            .await
+            .is_ok_and(|a| a.is_ok())
+    }
+
+    /// Whether polls are still finishing, i.e. the tracker is not wedged
+    #[must_use]
+    pub fn liveness(&self) -> HealthReport {
+        let mut report = self.report(None);
+        report.ok = report.polling;
+        report
+    }
+
+    /// Whether the database is reachable, and records are being written from a reachable source
+    pub async fn readiness(&self) -> HealthReport {
+        let mut report = self.report(Some(self.ping().await));
+        let now = get_second_timestamp(SystemTime::now());
+        let interval = SAMPLE_INTERVAL.load(Ordering::Relaxed);
+        let recording = self.no_write
+            || report.maintenance
+            || (!is_stale(report.last_record, self.started, now, interval)
+                && report.source_reachable != Some(false));
+        report.ok = report.database == Some(true) && report.polling && recording;
+        report
+    }
+
+    fn report(&self, database: Option<bool>) -> HealthReport {
+        let now = get_second_timestamp(SystemTime::now());
+        let last_poll = load(&LAST_POLL);
+        let last_record = load(&LAST_RECORD);
+        let last_source_failure = load(&LAST_SOURCE_FAILURE);
+        HealthReport {
+            ok: false,
+            database,
+            polling: self.no_write
+                || !is_stale(
+                    last_poll,
+                    self.started,
+                    now,
+                    SAMPLE_INTERVAL.load(Ordering::Relaxed),
+                ),
+            last_poll,
+            last_record,
+            seconds_since_last_record: last_record.map(|a| now.saturating_sub(a)),
+            source_reachable: match (last_record, last_source_failure) {
+                (None, None) => None,
+                (record, failure) => Some(record > failure),
+            },
+            maintenance: MAINTENANCE.load(Ordering::Relaxed),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::health::is_stale;
+
+    #[test]
+    pub fn staleness() {
+        assert!(!is_stale(None, 1000, 1100, 60));
+        assert!(is_stale(None, 1000, 1181, 60));
+        assert!(!is_stale(Some(1100), 1000, 1280, 60));
+        assert!(is_stale(Some(1100), 1000, 1281, 60));
+        assert!(!is_stale(Some(1000), 1000, 1900, 300));
+        assert!(is_stale(Some(1000), 1000, 1901, 300));
+    }
+}
@@ -3,12 +3,69 @@ use std::{
     sync::Arc,
 };
 
+use color_eyre::Report;
 use itertools::Itertools;
 use serde::{Deserialize, Serialize};
-use serde_big_array::BigArray;
 use smol_str::SmolStr;
 
-use crate::utils::{BitField64, Category, HourTimestamp};
+use crate::{
+    rollup::Rollup,
+    utils::{BitField64, Category, HourTimestamp, MinuteTimestamp, SecondTimestamp},
+};
+
+/// Why a minute has no record
+#[derive(Deserialize, Serialize, Debug, Clone, Copy, PartialEq, Eq, Hash)]
+#[serde(rename_all = "snake_case")]
+pub enum GapReason {
+    TrackerOffline,
+    SourceUnreachable,
+    SourceError,
+    Maintenance,
+    /// The source was polled, but the UUIDs of its players could not be looked up
+    LookupFailed,
+    /// The source was polled, but the record could not be written to the database
+    WriteFailed,
+}
+impl GapReason {
+    #[must_use]
+    pub fn from_source_error(e: &Report) -> Self {
+        match e.downcast_ref::<reqwest::Error>() {
+            Some(e) if e.is_connect() || e.is_timeout() || e.is_request() => {
+                Self::SourceUnreachable
+            }
+            _ => Self::SourceError,
+        }
+    }
+    /// The name used when serialised
+    #[must_use]
+    pub const fn as_str(self) -> &'static str {
+        match self {
+            Self::TrackerOffline => "tracker_offline",
+            Self::SourceUnreachable => "source_unreachable",
+            Self::SourceError => "source_error",
+            Self::Maintenance => "maintenance",
+            Self::LookupFailed => "lookup_failed",
+            Self::WriteFailed => "write_failed",
+        }
+    }
+    #[must_use]
+    pub const fn is_source_failure(self) -> bool {
+        matches!(self, Self::SourceUnreachable | Self::SourceError)
+    }
+    /// Whether the source was polled successfully, but the poll failed afterwards
+    #[must_use]
+    pub const fn is_tracker_failure(self) -> bool {
+        matches!(self, Self::LookupFailed | Self::WriteFailed)
+    }
+}
+
+/// A run of minutes, inclusive on both ends, with no records for the same reason
+#[derive(Deserialize, Serialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Gap {
+    pub from: MinuteTimestamp,
+    pub to: MinuteTimestamp,
+    pub reason: GapReason,
+}
 
 #[derive(Deserialize, Serialize, Debug, Clone, Default, PartialEq)]
 pub struct RollingAvgRecord {
@@ -56,6 +113,106 @@ impl From<&[AbsRecord]> for RollingAvgRecord {
     }
 }
 
+/// The spread of player counts within a window
+#[derive(Deserialize, Serialize, Debug, Clone, Default, PartialEq)]
+pub struct Band {
+    pub min: u32,
+    pub max: u32,
+    pub median: f32,
+    /// Values of the requested percentiles, keyed by percentile
+    pub percentiles: HashMap<u8, f32>,
+}
+impl Band {
+    fn new(mut counts: Vec<u32>, percentiles: &[u8]) -> Self {
+        counts.sort_unstable();
+        Self::from_sorted(counts.len(), |i| counts[i], percentiles)
+    }
+    /// From a histogram of the number of minutes with each count, which must not be empty
+    fn from_histogram(histogram: &[u32], percentiles: &[u8]) -> Self {
+        let len = histogram.iter().sum::<u32>() as usize;
+        let nth = |i: usize| {
+            let mut seen = 0;
+            for (count, minutes) in histogram.iter().enumerate() {
+                seen += *minutes as usize;
+                if seen > i {
+                    return count as u32;
+                }
+            }
+            histogram.len().saturating_sub(1) as u32
+        };
+        Self::from_sorted(len, nth, percentiles)
+    }
+    /// From `len` sorted counts, where `nth` returns the count at an index
+    fn from_sorted<F: Fn(usize) -> u32>(len: usize, nth: F, percentiles: &[u8]) -> Self {
+        let percentile = |p: u8| {
+            let rank = f32::from(p) / 100.0 * (len - 1) as f32;
+            let (lower, upper) = (nth(rank.floor() as usize), nth(rank.ceil() as usize));
+            (upper as f32 - lower as f32).mul_add(rank.fract(), lower as f32)
+        };
+        Self {
+            min: nth(0),
+            max: nth(len - 1),
+            median: percentile(50),
+            percentiles: percentiles.iter().map(|p| (*p, percentile(*p))).collect(),
+        }
+    }
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone, Default, PartialEq)]
+pub struct BandRecord {
+    pub all: Band,
+    pub categories: HashMap<Category, Band>,
+}
+impl BandRecord {
+    /// Returns `None` if there are no records. Percentiles must be at most 100.
+    #[must_use]
+    pub fn new(records: &[&AbsRecord], percentiles: &[u8]) -> Option<Self> {
+        if records.is_empty() {
+            return None;
+        }
+        Some(Self {
+            all: Band::new(
+                records.iter().map(|a| a.all.len() as u32).collect(),
+                percentiles,
+            ),
+            categories: records
+                .iter()
+                .flat_map(|a| a.categories.keys())
+                .unique()
+                .map(|cat| {
+                    let counts = records
+                        .iter()
+                        .map(|a| a.categories.get(cat).map_or(0, HashSet::len) as u32)
+                        .collect();
+                    (cat.to_owned(), Band::new(counts, percentiles))
+                })
+                .collect(),
+        })
+    }
+    /// The same as [`Self::new`] with every record in `rollups`. Returns `None` if there are no records.
+    #[must_use]
+    pub fn from_rollups(rollups: &[Rollup], percentiles: &[u8]) -> Option<Self> {
+        let first = rollups.first()?;
+        let rollup = Rollup::combine(first._id, rollups);
+        if rollup.samples == 0 {
+            return None;
+        }
+        Some(Self {
+            all: Band::from_histogram(&rollup.all.histogram, percentiles),
+            categories: rollup
+                .categories
+                .iter()
+                .map(|(cat, stats)| {
+                    (
+                        cat.to_owned(),
+                        Band::from_histogram(&stats.histogram, percentiles),
+                    )
+                })
+                .collect(),
+        })
+    }
+}
+
 #[derive(Deserialize, Serialize, Debug, Clone, Default, PartialEq, Eq)]
 pub struct AbsRecord {
     pub all: HashSet<usize>,
@@ -74,17 +231,77 @@ pub enum Record {
     },
 }
 
+impl Record {
+    /// The players that joined and left between two consecutive records
+    #[must_use]
+    pub fn delta(prev: &AbsRecord, record: &AbsRecord) -> Self {
+        let empty_hash_set = &HashSet::new();
+        Self::Delta {
+            joined: record.all.difference(&prev.all).copied().collect(),
+            joined_categories: {
+                let mut joined = HashMap::new();
+                for cat in record
+                    .categories
+                    .keys()
+                    .chain(prev.categories.keys())
+                    .sorted()
+                    .dedup()
+                {
+                    let joined_cat = record
+                        .categories
+                        .get(cat)
+                        .unwrap_or(empty_hash_set)
+                        .difference(prev.categories.get(cat).unwrap_or(empty_hash_set))
+                        .copied()
+                        .collect::<HashSet<_>>();
+                    if !joined_cat.is_empty() {
+                        joined.insert(cat.to_owned(), joined_cat);
+                    }
+                }
+                joined
+            },
+            left: prev.all.difference(&record.all).copied().collect(),
+            left_categories: {
+                let mut left = HashMap::new();
+                for cat in record
+                    .categories
+                    .keys()
+                    .chain(prev.categories.keys())
+                    .sorted()
+                    .dedup()
+                {
+                    let left_cat = prev
+                        .categories
+                        .get(cat)
+                        .unwrap_or(empty_hash_set)
+                        .difference(record.categories.get(cat).unwrap_or(empty_hash_set))
+                        .copied()
+                        .collect::<HashSet<_>>();
+                    if !left_cat.is_empty() {
+                        left.insert(cat.to_owned(), left_cat);
+                    }
+                }
+                left
+            },
+        }
+    }
+}
+
 impl Default for Record {
     fn default() -> Self {
         Self::Abs(AbsRecord::default())
     }
 }
 
+pub const DEFAULT_RESOLUTION: u32 = 60;
+
 #[derive(Deserialize, Serialize, Debug, Clone, PartialEq, Eq)]
 pub struct Hour {
     pub _id: HourTimestamp,
-    #[serde(with = "BigArray")]
-    pub records: [Option<Arc<AbsRecord>>; 60],
+    /// Number of seconds each slot covers
+    pub resolution: u32,
+    pub records: Vec<Option<Arc<AbsRecord>>>,
+    pub gaps: Vec<Option<GapReason>>,
 }
 impl Hour {
     #[must_use]
@@ -94,20 +311,71 @@ impl Hour {
             ..Self::default()
         }
     }
+    #[must_use]
+    pub fn with_resolution(timestamp: HourTimestamp, resolution: u32) -> Self {
+        let slots = (3600 / resolution) as usize;
+        Self {
+            _id: timestamp,
+            resolution,
+            records: vec![None; slots],
+            gaps: vec![None; slots],
+        }
+    }
+    /// Sets the record of the slot of `ts`, replacing any gap there
+    pub fn set_record(&mut self, ts: SecondTimestamp, record: AbsRecord) {
+        let slot = ((ts % 3600) / u64::from(self.resolution)) as usize;
+        self.records[slot] = Some(Arc::new(record));
+        self.gaps[slot] = None;
+    }
+    /// Sets the gaps of the slots in this hour from `from` to `to` inclusive that have no record
+    pub fn set_gaps(&mut self, from: SecondTimestamp, to: SecondTimestamp, reason: GapReason) {
+        let h_start = u64::from(self._id) * 3600;
+        let from_slot = (from.max(h_start) - h_start) / u64::from(self.resolution);
+        let to_slot = (to.min(h_start + 3599) - h_start) / u64::from(self.resolution);
+        for slot in from_slot as usize..=to_slot as usize {
+            if self.records[slot].is_none() {
+                self.gaps[slot] = Some(reason);
+            }
+        }
+    }
+    /// Converts the hour to another resolution.
+    /// Slots that are split up are copied into every new slot they cover,
+    /// and slots that are merged take the latest record among them.
+    #[must_use]
+    pub fn resample(&self, resolution: u32) -> Self {
+        if resolution == self.resolution {
+            return self.to_owned();
+        }
+        let mut hour = Self::with_resolution(self._id, resolution);
+        for (i, (record, gap)) in hour.records.iter_mut().zip(&mut hour.gaps).enumerate() {
+            let start = i * resolution as usize / self.resolution as usize;
+            let end = ((i + 1) * resolution as usize)
+                .div_ceil(self.resolution as usize)
+                .max(start + 1);
+            let (records, gaps) = (&self.records[start..end], &self.gaps[start..end]);
+            *record = records.iter().rev().find_map(Clone::clone);
+            if record.is_none() {
+                *gap = gaps.iter().find_map(|a| *a);
+            }
+        }
+        hour
+    }
 }
 impl Default for Hour {
     fn default() -> Self {
-        Self {
-            _id: HourTimestamp::default(),
-            records: [(); 60].map(|_| None),
-        }
+        Self::with_resolution(HourTimestamp::default(), DEFAULT_RESOLUTION)
     }
 }
 impl From<HourDef> for Hour {
     #[allow(clippy::unwrap_in_result)]
     #[tracing::instrument]
     fn from(value: HourDef) -> Self {
-        let mut hour = Self::new(value._id);
+        let resolution = if value.resolution == 0 || !3600u32.is_multiple_of(value.resolution) {
+            DEFAULT_RESOLUTION
+        } else {
+            value.resolution
+        };
+        let mut hour = Self::with_resolution(value._id, resolution);
         let prev_record: fn(usize, &Self) -> Option<Arc<AbsRecord>> = |m, h| {
             if m == 0 {
                 None
@@ -115,9 +383,14 @@ impl From<HourDef> for Hour {
                 h.records[m - 1].to_owned()
             }
         };
-        for m in 0usize..=59 {
+        for (m, reason) in &value.gaps {
+            if let Some(gap) = m.parse::<usize>().ok().and_then(|m| hour.gaps.get_mut(m)) {
+                *gap = Some(*reason);
+            }
+        }
+        for m in 0..hour.records.len() {
             let record = value.deltas.get(&*m.to_string());
-            if !value.tracked_mins.is_on(m.try_into().unwrap()) {
+            if !value.is_tracked(m) {
                 continue;
             }
             hour.records[m] = match record {
@@ -151,81 +424,147 @@ impl From<HourDef> for Hour {
     }
 }
 
-#[derive(Deserialize, Serialize, Debug, Clone, Default, PartialEq, Eq)]
+pub(crate) const fn default_resolution() -> u32 {
+    DEFAULT_RESOLUTION
+}
+#[allow(clippy::trivially_copy_pass_by_ref)]
+const fn is_default_resolution(resolution: &u32) -> bool {
+    *resolution == DEFAULT_RESOLUTION
+}
+
+/// Slot keys in `deltas` and `gaps` are indices into the slots of the hour.
+/// `resolution` is left out for hours with the default resolution, so that they stay readable by older versions.
+/// As empty fields are left out, stored hours have to be replaced as a whole when saved.
+#[derive(Deserialize, Serialize, Debug, Clone, PartialEq, Eq)]
 pub struct HourDef {
     pub _id: HourTimestamp,
+    #[serde(
+        default = "default_resolution",
+        skip_serializing_if = "is_default_resolution"
+    )]
+    pub resolution: u32,
+    /// Which of the first 60 slots are tracked
     pub tracked_mins: BitField64,
+    /// Which of the slots after the first 60 are tracked, 60 slots per element
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub tracked_slots: Vec<BitField64>,
     pub deltas: HashMap<SmolStr, Record>,
+    #[serde(default, skip_serializing_if = "HashMap::is_empty")]
+    pub gaps: HashMap<SmolStr, GapReason>,
+}
+impl HourDef {
+    #[must_use]
+    pub fn is_tracked(&self, slot: usize) -> bool {
+        let bit = i32::try_from(slot % 60).unwrap();
+        match slot / 60 {
+            0 => self.tracked_mins.is_on(bit),
+            i => self
+                .tracked_slots
+                .get(i - 1)
+                .is_some_and(|bits| bits.is_on(bit)),
+        }
+    }
+    pub fn track(&mut self, slot: usize) {
+        let bit = i32::try_from(slot % 60).unwrap();
+        match slot / 60 {
+            0 => self.tracked_mins.turn_on(bit),
+            i => {
+                if self.tracked_slots.len() < i {
+                    self.tracked_slots.resize(i, BitField64::default());
+                }
+                self.tracked_slots[i - 1].turn_on(bit);
+            }
+        }
+    }
+    /// Everything wrong with the hour, such as records that cannot be read or players missing from a name map of length `players`
+    #[must_use]
+    pub fn problems(&self, players: usize) -> Vec<String> {
+        let mut problems = vec![];
+        if self.resolution == 0 || !3600u32.is_multiple_of(self.resolution) {
+            problems.push(format!("Invalid resolution {}", self.resolution));
+            return problems;
+        }
+        let slots = (3600 / self.resolution) as usize;
+        let slot_of = |key: &SmolStr| key.parse::<usize>().ok().filter(|a| *a < slots);
+        for key in self.deltas.keys().chain(self.gaps.keys()) {
+            if slot_of(key).is_none() {
+                problems.push(format!("Invalid slot `{key}`"));
+            }
+        }
+        let check_ids = |ids: &HashSet<usize>, problems: &mut Vec<String>, slot: usize| {
+            if let Some(id) = ids.iter().find(|a| **a >= players) {
+                problems.push(format!("Slot {slot} has player {id} not in the name map"));
+            }
+        };
+        for slot in 0..slots {
+            let key = SmolStr::from(slot.to_string());
+            let tracked = self.is_tracked(slot);
+            match (tracked, self.deltas.get(&key)) {
+                (true, None) => problems.push(format!("Slot {slot} is tracked but has no record")),
+                (false, Some(_)) => {
+                    problems.push(format!("Slot {slot} has a record but is not tracked"));
+                }
+                (_, Some(Record::Abs(record))) => {
+                    check_ids(&record.all, &mut problems, slot);
+                    for ids in record.categories.values() {
+                        check_ids(ids, &mut problems, slot);
+                    }
+                }
+                (_, Some(Record::Delta { joined, left, .. })) => {
+                    if slot == 0 || !self.is_tracked(slot - 1) {
+                        problems.push(format!(
+                            "Slot {slot} has a delta without a record before it"
+                        ));
+                    }
+                    check_ids(joined, &mut problems, slot);
+                    check_ids(left, &mut problems, slot);
+                }
+                (false, None) => {}
+            }
+            if tracked && self.gaps.contains_key(&key) {
+                problems.push(format!("Slot {slot} has both a record and a gap"));
+            }
+        }
+        problems
+    }
+}
+impl Default for HourDef {
+    fn default() -> Self {
+        Self {
+            _id: HourTimestamp::default(),
+            resolution: DEFAULT_RESOLUTION,
+            tracked_mins: BitField64::default(),
+            tracked_slots: Vec::new(),
+            deltas: HashMap::new(),
+            gaps: HashMap::new(),
+        }
+    }
 }
 impl From<Hour> for HourDef {
     fn from(value: Hour) -> Self {
         let mut hour = Self {
             _id: value._id,
+            resolution: value.resolution,
             ..Self::default()
         };
-        let empty_hash_set = &HashSet::new();
         let mut prev_record: Option<Arc<AbsRecord>> = None;
 
         for (i, record) in value.records.into_iter().enumerate() {
             let Some(record) = record else {
+                if let Some(reason) = value.gaps[i] {
+                    hour.gaps.insert(i.to_string().into(), reason);
+                }
                 prev_record = None;
                 continue;
             };
 
-            hour.tracked_mins.turn_on(i.try_into().unwrap());
+            hour.track(i);
 
             if i == 0 || prev_record.is_none() {
                 hour.deltas
                     .insert(i.to_string().into(), Record::Abs((*record).to_owned()));
             } else if let Some(prev) = prev_record {
-                let delta = Record::Delta {
-                    joined: record.all.difference(&prev.all).copied().collect(),
-                    joined_categories: {
-                        let mut joined = HashMap::new();
-                        for cat in record
-                            .categories
-                            .keys()
-                            .chain(prev.categories.keys())
-                            .sorted()
-                            .dedup()
-                        {
-                            let joined_cat = record
-                                .categories
-                                .get(cat)
-                                .unwrap_or(empty_hash_set)
-                                .difference(prev.categories.get(cat).unwrap_or(empty_hash_set))
-                                .copied()
-                                .collect::<HashSet<_>>();
-                            if !joined_cat.is_empty() {
-                                joined.insert(cat.to_owned(), joined_cat);
-                            }
-                        }
-                        joined
-                    },
-                    left: prev.all.difference(&record.all).copied().collect(),
-                    left_categories: {
-                        let mut left = HashMap::new();
-                        for cat in record
-                            .categories
-                            .keys()
-                            .chain(prev.categories.keys())
-                            .sorted()
-                            .dedup()
-                        {
-                            let left_cat = prev
-                                .categories
-                                .get(cat)
-                                .unwrap_or(empty_hash_set)
-                                .difference(record.categories.get(cat).unwrap_or(empty_hash_set))
-                                .copied()
-                                .collect::<HashSet<_>>();
-                            if !left_cat.is_empty() {
-                                left.insert(cat.to_owned(), left_cat);
-                            }
-                        }
-                        left
-                    },
-                };
+                let delta = Record::delta(&prev, &record);
                 hour.deltas.insert(i.to_string().into(), delta);
             }
             prev_record = Some(Arc::clone(&record));
@@ -241,8 +580,10 @@ mod tests {
         sync::Arc,
     };
 
+    use mongodb::bson::{from_document, to_document};
+
     use crate::{
-        hour::{AbsRecord, Hour, HourDef, Record},
+        hour::{AbsRecord, BandRecord, GapReason, Hour, HourDef, Record},
         utils::BitField64,
     };
 
@@ -284,6 +625,8 @@ mod tests {
                 );
                 d
             },
+            gaps: HashMap::from([("2".into(), GapReason::SourceError)]),
+            ..HourDef::default()
         };
         let d = Hour::from(hd);
         assert_eq!(
@@ -301,6 +644,7 @@ mod tests {
             })
         );
         assert_eq!(d.records[2].as_ref().map(|a| (**a).to_owned()), None);
+        assert_eq!(d.gaps[2], Some(GapReason::SourceError));
         assert_eq!(
             d.records[3].as_ref().map(|a| (**a).to_owned()),
             Some(AbsRecord {
@@ -313,8 +657,9 @@ mod tests {
     pub fn hour_to_hour_def() {
         let h = Hour {
             _id: 0,
+            resolution: 60,
             records: {
-                let mut r = [(); 60].map(|_| None);
+                let mut r = vec![None; 60];
                 r[0] = Some(Arc::new(AbsRecord {
                     all: HashSet::from([0]),
                     categories: HashMap::default(),
@@ -329,6 +674,11 @@ mod tests {
                 }));
                 r
             },
+            gaps: {
+                let mut g = vec![None; 60];
+                g[2] = Some(GapReason::SourceError);
+                g
+            },
         };
         let hd = HourDef::from(h);
         assert_eq!(
@@ -369,7 +719,166 @@ mod tests {
                     );
                     d
                 },
+                gaps: HashMap::from([("2".into(), GapReason::SourceError)]),
+                ..HourDef::default()
             }
         );
     }
+    #[test]
+    pub fn sub_minute_round_trip() {
+        let mut h = Hour::with_resolution(0, 15);
+        h.records[0] = Some(Arc::new(AbsRecord {
+            all: HashSet::from([0]),
+            categories: HashMap::default(),
+        }));
+        h.records[100] = Some(Arc::new(AbsRecord {
+            all: HashSet::from([1]),
+            categories: HashMap::default(),
+        }));
+        h.records[101] = Some(Arc::new(AbsRecord {
+            all: HashSet::from([1, 2]),
+            categories: HashMap::default(),
+        }));
+        h.gaps[239] = Some(GapReason::Maintenance);
+        let hd = HourDef::from(h.to_owned());
+        assert_eq!(hd.resolution, 15);
+        assert!(hd.is_tracked(0));
+        assert!(hd.is_tracked(100));
+        assert!(hd.is_tracked(101));
+        assert!(!hd.is_tracked(102));
+        assert_eq!(Hour::from(hd), h);
+    }
+    #[test]
+    pub fn retry_after_gap() {
+        let mut hour = Hour::new(1);
+        hour.set_gaps(3600 + 120, 3600 + 120, GapReason::SourceError);
+        let stored = to_document(&HourDef::from(hour)).unwrap();
+        assert!(stored.contains_key("gaps"));
+
+        let mut hour = Hour::from(from_document::<HourDef>(stored).unwrap());
+        hour.set_record(
+            3600 + 120,
+            AbsRecord {
+                all: HashSet::from([0]),
+                categories: HashMap::default(),
+            },
+        );
+        let hour_def = HourDef::from(hour);
+        assert!(hour_def.gaps.is_empty());
+        assert!(hour_def.problems(1).is_empty());
+        // Empty gaps are left out, which is why hours are replaced instead of merged when saved
+        let stored = to_document(&hour_def).unwrap();
+        assert!(!stored.contains_key("gaps"));
+        assert_eq!(from_document::<HourDef>(stored).unwrap(), hour_def);
+    }
+    #[test]
+    pub fn resample_stored_hour() {
+        let mut h = Hour::with_resolution(0, 15);
+        for (slot, id) in [(0, 0), (3, 1), (100, 2)] {
+            h.set_record(
+                slot * 15,
+                AbsRecord {
+                    all: HashSet::from([id]),
+                    categories: HashMap::default(),
+                },
+            );
+        }
+        let stored = to_document(&HourDef::from(h)).unwrap();
+        assert!(stored.contains_key("resolution"));
+        assert!(stored.contains_key("tracked_slots"));
+
+        let minutes = Hour::from(from_document::<HourDef>(stored).unwrap()).resample(60);
+        let stored = to_document(&HourDef::from(minutes.to_owned())).unwrap();
+        assert!(!stored.contains_key("resolution"));
+        assert!(!stored.contains_key("tracked_slots"));
+        let read = Hour::from(from_document::<HourDef>(stored).unwrap());
+        assert_eq!(read, minutes);
+        assert_eq!(read.records.len(), 60);
+        assert_eq!(read.records[0].as_ref().unwrap().all, HashSet::from([1]));
+        assert_eq!(read.records[25].as_ref().unwrap().all, HashSet::from([2]));
+    }
+    #[test]
+    pub fn resample_hour() {
+        let mut h = Hour::with_resolution(0, 30);
+        h.records[0] = Some(Arc::new(AbsRecord {
+            all: HashSet::from([0]),
+            categories: HashMap::default(),
+        }));
+        h.records[1] = Some(Arc::new(AbsRecord {
+            all: HashSet::from([1]),
+            categories: HashMap::default(),
+        }));
+        h.gaps[2] = Some(GapReason::SourceError);
+        h.gaps[3] = Some(GapReason::SourceError);
+        h.records[11] = Some(Arc::new(AbsRecord {
+            all: HashSet::from([2]),
+            categories: HashMap::default(),
+        }));
+
+        let minutes = h.resample(60);
+        assert_eq!(minutes.records.len(), 60);
+        assert_eq!(minutes.records[0], h.records[1]);
+        assert_eq!(minutes.records[1], None);
+        assert_eq!(minutes.gaps[1], Some(GapReason::SourceError));
+        assert_eq!(minutes.records[5], h.records[11]);
+
+        let five_minutes = h.resample(300);
+        assert_eq!(five_minutes.records.len(), 12);
+        assert_eq!(five_minutes.records[0], h.records[1]);
+        assert_eq!(five_minutes.records[1], h.records[11]);
+
+        let back = five_minutes.resample(60);
+        assert_eq!(back.records[0..5], vec![h.records[1].to_owned(); 5]);
+        assert_eq!(back.records[5..10], vec![h.records[11].to_owned(); 5]);
+    }
+    #[test]
+    pub fn band_percentiles() {
+        let records = [
+            AbsRecord {
+                all: HashSet::from([0]),
+                categories: HashMap::from([("staff".into(), HashSet::from([0]))]),
+            },
+            AbsRecord {
+                all: HashSet::from([0, 1, 2, 3]),
+                categories: HashMap::default(),
+            },
+            AbsRecord {
+                all: HashSet::from([0, 1]),
+                categories: HashMap::default(),
+            },
+        ];
+        let band = BandRecord::new(&records.iter().collect::<Vec<_>>(), &[25, 100]).unwrap();
+        assert_eq!((band.all.min, band.all.max, band.all.median), (1, 4, 2.0));
+        assert_eq!(band.all.percentiles, HashMap::from([(25, 1.5), (100, 4.0)]));
+        let staff = &band.categories["staff"];
+        assert_eq!((staff.min, staff.max, staff.median), (0, 1, 0.0));
+        assert!(BandRecord::new(&[], &[]).is_none());
+    }
+    #[test]
+    pub fn hour_def_problems() {
+        let mut hour = Hour::new(0);
+        hour.records[0] = Some(Arc::new(AbsRecord {
+            all: HashSet::from([0, 1]),
+            categories: HashMap::default(),
+        }));
+        hour.records[1] = Some(Arc::new(AbsRecord {
+            all: HashSet::from([1]),
+            categories: HashMap::default(),
+        }));
+        let mut hour_def = HourDef::from(hour);
+        assert!(hour_def.problems(2).is_empty());
+        assert_eq!(
+            hour_def.problems(1),
+            vec!["Slot 0 has player 1 not in the name map".to_owned()]
+        );
+        hour_def.deltas.remove("0");
+        hour_def.gaps.insert("1".into(), GapReason::Maintenance);
+        assert_eq!(
+            hour_def.problems(2),
+            vec![
+                "Slot 0 is tracked but has no record".to_owned(),
+                "Slot 1 has both a record and a gap".to_owned(),
+            ]
+        );
+    }
 }
@@ -155,38 +155,40 @@
     clippy::wildcard_dependencies
 )]
 
+pub mod cli;
 mod config;
 mod database;
+pub mod events;
+pub mod export;
+pub mod health;
 pub mod hour;
+pub mod metrics;
 pub mod name_to_uuid;
+pub mod outage;
+pub mod peaks;
+pub mod reload;
+pub mod rollup;
+pub mod scheduler;
 pub mod server;
+pub mod stats;
 pub mod tracker;
 pub mod utils;
+pub mod webhooks;
 
-use std::path::PathBuf;
-
-use color_eyre::eyre::{eyre, Result};
+use clap::Parser;
+use color_eyre::eyre::Result;
 use tracing_subscriber::{filter::EnvFilter, fmt, layer::SubscriberExt, util::SubscriberInitExt};
 
-use crate::tracker::StatusTracker;
+use crate::cli::Cli;
 
 #[tokio::main]
 async fn main() -> Result<()> {
     color_eyre::install()?;
     let _ = dotenvy::dotenv();
     tracing_subscriber::registry()
-        .with(fmt::layer().compact())
+        .with(fmt::layer().compact().with_writer(std::io::stderr))
         .with(EnvFilter::from_env("RUST_LOG"))
         .init();
 
-    let path = if let Some(path) = std::env::args().nth(1) {
-        path.parse::<PathBuf>()?
-    } else {
-        PathBuf::from("./statustracker.toml")
-    };
-    let file = std::fs::read_to_string(&path)
-        .map_err(|e| eyre!("Error opening {}: {e}", path.display()))?;
-
-    server::start_server(StatusTracker::new(toml::from_str(&file)?).await?).await?;
-    Ok(())
+    Cli::parse().run().await
 }
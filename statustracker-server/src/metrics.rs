@@ -0,0 +1,122 @@
+use color_eyre::eyre::Result;
+use once_cell::sync::Lazy;
+use prometheus::{
+    core::Collector, Encoder, Histogram, HistogramOpts, IntCounterVec, IntGauge, IntGaugeVec, Opts,
+    Registry, TextEncoder,
+};
+
+pub static REGISTRY: Lazy<Registry> = Lazy::new(Registry::new);
+
+fn register<T: Collector + Clone + 'static>(metric: T) -> T {
+    REGISTRY.register(Box::new(metric.clone())).unwrap();
+    metric
+}
+
+pub static ONLINE_PLAYERS: Lazy<IntGauge> = Lazy::new(|| {
+    register(IntGauge::new("statustracker_online_players", "Number of players online").unwrap())
+});
+pub static CATEGORY_ONLINE_PLAYERS: Lazy<IntGaugeVec> = Lazy::new(|| {
+    register(
+        IntGaugeVec::new(
+            Opts::new(
+                "statustracker_category_online_players",
+                "Number of players in each category online",
+            ),
+            &["category"],
+        )
+        .unwrap(),
+    )
+});
+pub static POLL_DURATION: Lazy<Histogram> = Lazy::new(|| {
+    register(
+        Histogram::with_opts(HistogramOpts::new(
+            "statustracker_poll_duration_seconds",
+            "How long each poll took, including writing to the database",
+        ))
+        .unwrap(),
+    )
+});
+pub static SOURCE_FAILURES: Lazy<IntCounterVec> = Lazy::new(|| {
+    register(
+        IntCounterVec::new(
+            Opts::new(
+                "statustracker_source_failures_total",
+                "Number of polls where the source could not be read",
+            ),
+            &["reason"],
+        )
+        .unwrap(),
+    )
+});
+pub static MOJANG_FAILURES: Lazy<IntCounterVec> = Lazy::new(|| {
+    register(
+        IntCounterVec::new(
+            Opts::new(
+                "statustracker_mojang_failures_total",
+                "Number of failed requests to the Mojang API",
+            ),
+            &["endpoint"],
+        )
+        .unwrap(),
+    )
+});
+pub static NAME_CACHE_SIZE: Lazy<IntGauge> = Lazy::new(|| {
+    register(
+        IntGauge::new(
+            "statustracker_name_cache_size",
+            "Number of names in the name to UUID cache",
+        )
+        .unwrap(),
+    )
+});
+pub static NAME_MAP_SIZE: Lazy<IntGauge> = Lazy::new(|| {
+    register(
+        IntGauge::new(
+            "statustracker_name_map_size",
+            "Number of players in the name map",
+        )
+        .unwrap(),
+    )
+});
+pub static LAST_WRITE: Lazy<IntGauge> = Lazy::new(|| {
+    register(
+        IntGauge::new(
+            "statustracker_last_write_timestamp_seconds",
+            "Second timestamp of the latest record written to the database",
+        )
+        .unwrap(),
+    )
+});
+
+/// Sets a gauge to a value that should always fit
+pub fn set<T: TryInto<i64>>(gauge: &IntGauge, value: T) {
+    gauge.set(value.try_into().unwrap_or(i64::MAX));
+}
+
+/// Every metric in the Prometheus text format
+pub fn gather() -> Result<String> {
+    Lazy::force(&ONLINE_PLAYERS);
+    Lazy::force(&CATEGORY_ONLINE_PLAYERS);
+    Lazy::force(&POLL_DURATION);
+    Lazy::force(&SOURCE_FAILURES);
+    Lazy::force(&MOJANG_FAILURES);
+    Lazy::force(&NAME_CACHE_SIZE);
+    Lazy::force(&NAME_MAP_SIZE);
+    Lazy::force(&LAST_WRITE);
+    let mut buf = vec![];
+    TextEncoder::new().encode(&REGISTRY.gather(), &mut buf)?;
+    Ok(String::from_utf8(buf)?)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::metrics::{gather, set, ONLINE_PLAYERS};
+
+    #[test]
+    pub fn gather_metrics() {
+        set(&ONLINE_PLAYERS, 5usize);
+        let text = gather().unwrap();
+        assert!(text.contains("statustracker_online_players 5"));
+        assert!(text.contains("# TYPE statustracker_poll_duration_seconds histogram"));
+    }
+}
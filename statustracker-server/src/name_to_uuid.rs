@@ -1,4 +1,8 @@
-use std::collections::HashMap;
+use std::{
+    collections::{HashMap, VecDeque},
+    hash::Hash,
+    time::{Duration, Instant},
+};
 
 use color_eyre::eyre::{eyre, Result};
 use once_cell::sync::Lazy;
@@ -6,35 +10,217 @@ use reqwest::StatusCode;
 use serde_json::{Map, Value};
 use smol_str::SmolStr;
 use tokio::sync::RwLock;
-use tracing::{debug, trace};
+use tracing::{debug, trace, warn};
 use uuid::Uuid;
 
-pub static NAME_CACHE: Lazy<RwLock<HashMap<SmolStr, Uuid>>> = Lazy::new(Default::default);
+use crate::metrics::{set, MOJANG_FAILURES, NAME_CACHE_SIZE};
+
+static NAME_CACHE: Lazy<RwLock<NameCache>> = Lazy::new(Default::default);
+/// Names and UUIDs the API had no profile for
+static MISSING_NAMES: Lazy<RwLock<MissingCache<SmolStr>>> =
+    Lazy::new(|| RwLock::new(MissingCache::new(MISSING_CAPACITY)));
+static MISSING_UUIDS: Lazy<RwLock<MissingCache<Uuid>>> =
+    Lazy::new(|| RwLock::new(MissingCache::new(MISSING_CAPACITY)));
+/// How long a missing profile is remembered before it is looked up again
+const MISSING_TTL: Duration = Duration::from_secs(60 * 60);
+const MISSING_CAPACITY: usize = 4096;
+
+/// Names and UUIDs of players that were looked up, in both directions
+#[derive(Debug, Default)]
+struct NameCache {
+    uuids: HashMap<SmolStr, Uuid>,
+    names: HashMap<Uuid, SmolStr>,
+}
+impl NameCache {
+    fn insert(&mut self, name: SmolStr, uuid: Uuid) {
+        if let Some(old) = self.names.insert(uuid, name.to_owned()) {
+            self.uuids.remove(&old);
+        }
+        if let Some(old) = self.uuids.insert(name, uuid) {
+            if old != uuid {
+                self.names.remove(&old);
+            }
+        }
+        set(&NAME_CACHE_SIZE, self.uuids.len());
+    }
+}
+
+/// Keys that were looked up and not found, forgotten after [`MISSING_TTL`] or when past `capacity`
+#[derive(Debug)]
+struct MissingCache<K> {
+    capacity: usize,
+    looked_up: HashMap<K, Instant>,
+    order: VecDeque<(K, Instant)>,
+}
+impl<K: Eq + Hash + Clone> MissingCache<K> {
+    fn new(capacity: usize) -> Self {
+        Self {
+            capacity,
+            looked_up: HashMap::new(),
+            order: VecDeque::new(),
+        }
+    }
+    fn contains(&self, key: &K, now: Instant) -> bool {
+        self.looked_up
+            .get(key)
+            .is_some_and(|a| now.duration_since(*a) < MISSING_TTL)
+    }
+    /// Inserts `key`, removing expired keys and then the oldest ones past `capacity`
+    fn insert(&mut self, key: K, now: Instant) {
+        self.looked_up.insert(key.to_owned(), now);
+        self.order.push_back((key, now));
+        while let Some((key, looked_up)) = self.order.front() {
+            if now.duration_since(*looked_up) < MISSING_TTL && self.looked_up.len() <= self.capacity
+            {
+                break;
+            }
+            // Keys looked up again are further back in the queue too
+            if self.looked_up.get(key) == Some(looked_up) {
+                self.looked_up.remove(key);
+            }
+            self.order.pop_front();
+        }
+    }
+}
 
 #[tracing::instrument]
 pub async fn name_to_uuid(name: &str) -> Result<Option<Uuid>> {
-    let mut cache = NAME_CACHE.write().await;
-    Ok(if let Some(id) = cache.get(name) {
+    if let Some(id) = NAME_CACHE.read().await.uuids.get(name) {
         debug!(%name, "Retrieving uuid from cache");
-        Some(*id)
+        return Ok(Some(*id));
+    }
+    if MISSING_NAMES
+        .read()
+        .await
+        .contains(&name.into(), Instant::now())
+    {
+        return Ok(None);
+    }
+    debug!(%name, "Retrieving uuid from API");
+    let id = fetch_uuid(name).await.inspect_err(|_| {
+        MOJANG_FAILURES.with_label_values(&["profile"]).inc();
+    })?;
+    if let Some(id) = id {
+        NAME_CACHE.write().await.insert(name.into(), id);
     } else {
-        debug!(%name, "Retrieving uuid from API");
-        let req = reqwest::get(format!(
-            "https://api.mojang.com/users/profiles/minecraft/{name}"
-        ))
-        .await?;
-        if req.status() == StatusCode::BAD_REQUEST {
-            return Ok(None);
-        }
-        let json: Map<String, Value> = req.json().await?;
-        trace!(%name, ?json);
-        let id = json
-            .get("id")
+        MISSING_NAMES
+            .write()
+            .await
+            .insert(name.into(), Instant::now());
+    }
+    Ok(id)
+}
+
+async fn fetch_uuid(name: &str) -> Result<Option<Uuid>> {
+    let req = reqwest::get(format!(
+        "https://api.mojang.com/users/profiles/minecraft/{name}"
+    ))
+    .await?;
+    if req.status() == StatusCode::BAD_REQUEST {
+        return Ok(None);
+    }
+    let json: Map<String, Value> = req.json().await?;
+    trace!(%name, ?json);
+    Ok(Some(
+        json.get("id")
             .ok_or_else(|| eyre!("No field `id`"))?
             .as_str()
             .ok_or_else(|| eyre!("Field `id` is not string"))?
-            .parse::<Uuid>()?;
-        cache.insert(name.into(), id);
-        Some(id)
-    })
+            .parse::<Uuid>()?,
+    ))
+}
+
+/// The name of `uuid` from the cache, without calling the API
+pub async fn cached_name(uuid: Uuid) -> Option<SmolStr> {
+    NAME_CACHE.read().await.names.get(&uuid).cloned()
+}
+
+#[tracing::instrument]
+pub async fn uuid_to_name(uuid: Uuid) -> Result<Option<SmolStr>> {
+    if let Some(name) = cached_name(uuid).await {
+        debug!(%uuid, "Retrieving name from cache");
+        return Ok(Some(name));
+    }
+    if MISSING_UUIDS.read().await.contains(&uuid, Instant::now()) {
+        return Ok(None);
+    }
+    debug!(%uuid, "Retrieving name from API");
+    let name = fetch_name(uuid).await.inspect_err(|_| {
+        MOJANG_FAILURES.with_label_values(&["session"]).inc();
+    })?;
+    if let Some(name) = &name {
+        NAME_CACHE.write().await.insert(name.to_owned(), uuid);
+    } else {
+        MISSING_UUIDS.write().await.insert(uuid, Instant::now());
+    }
+    Ok(name)
+}
+
+/// [`uuid_to_name`], with failed lookups logged and returned as `None`, for responses that also carry the UUID
+pub async fn try_uuid_to_name(uuid: Uuid) -> Option<SmolStr> {
+    uuid_to_name(uuid)
+        .await
+        .inspect_err(|e| warn!(%uuid, "Could not look up name: {e}"))
+        .ok()
+        .flatten()
+}
+
+async fn fetch_name(uuid: Uuid) -> Result<Option<SmolStr>> {
+    let req = reqwest::get(format!(
+        "https://sessionserver.mojang.com/session/minecraft/profile/{}",
+        uuid.simple()
+    ))
+    .await?;
+    if req.status() == StatusCode::NO_CONTENT
+        || req.status() == StatusCode::NOT_FOUND
+        || req.status() == StatusCode::BAD_REQUEST
+    {
+        return Ok(None);
+    }
+    let json: Map<String, Value> = req.json().await?;
+    trace!(%uuid, ?json);
+    Ok(Some(
+        json.get("name")
+            .ok_or_else(|| eyre!("No field `name`"))?
+            .as_str()
+            .ok_or_else(|| eyre!("Field `name` is not string"))?
+            .into(),
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::{Duration, Instant};
+
+    use uuid::Uuid;
+
+    use crate::name_to_uuid::{MissingCache, NameCache, MISSING_TTL};
+
+    #[test]
+    pub fn missing_cache() {
+        let now = Instant::now();
+        let mut cache = MissingCache::new(2);
+        cache.insert(1, now);
+        cache.insert(2, now + Duration::from_secs(1));
+        cache.insert(1, now + Duration::from_secs(2));
+        assert!(cache.contains(&1, now + MISSING_TTL));
+        assert!(cache.contains(&2, now + Duration::from_secs(1)));
+        cache.insert(3, now + Duration::from_secs(3));
+        assert!(!cache.contains(&2, now + Duration::from_secs(3)));
+        assert_eq!(cache.looked_up.len(), 2);
+        cache.insert(4, now + MISSING_TTL + Duration::from_secs(3));
+        assert_eq!(cache.looked_up.keys().copied().collect::<Vec<_>>(), vec![4]);
+        assert_eq!(cache.order.len(), 1);
+    }
+    #[test]
+    pub fn name_cache() {
+        let (a, b) = (Uuid::from_bytes([1; 16]), Uuid::from_bytes([2; 16]));
+        let mut cache = NameCache::default();
+        cache.insert("Steve".into(), a);
+        cache.insert("Alex".into(), a);
+        cache.insert("Steve".into(), b);
+        assert_eq!(cache.names.get(&a), Some(&"Alex".into()));
+        assert_eq!(cache.names.get(&b), Some(&"Steve".into()));
+        assert_eq!(cache.uuids.len(), 2);
+    }
 }
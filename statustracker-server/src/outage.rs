@@ -0,0 +1,100 @@
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    hour::{default_resolution, GapReason},
+    utils::{MinuteTimestamp, SecondTimestamp},
+};
+
+/// A run of consecutive minutes in which polling failed, usually because the source could not be polled
+#[derive(Deserialize, Serialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Outage {
+    #[serde(rename = "_id")]
+    pub from: MinuteTimestamp,
+    pub to: MinuteTimestamp,
+    /// The reason of the latest failure
+    pub reason: GapReason,
+    /// Number of polled intervals that failed, not counting retries
+    pub failed_polls: u32,
+    /// Seconds between polls while the outage went on
+    #[serde(default = "default_resolution")]
+    pub interval: u32,
+    /// Start of the latest interval that failed
+    #[serde(default)]
+    pub latest: SecondTimestamp,
+}
+impl Outage {
+    #[must_use]
+    pub const fn new(ts: SecondTimestamp, reason: GapReason, interval: u32) -> Self {
+        Self {
+            from: ts / 60,
+            to: ts / 60,
+            reason,
+            failed_polls: 1,
+            interval,
+            latest: ts,
+        }
+    }
+    /// Extends the outage with a failure of the interval starting at `ts`, returning `false` if it is not part of this outage.
+    /// Retries of the latest interval only update the reason.
+    pub const fn extend(&mut self, ts: SecondTimestamp, reason: GapReason) -> bool {
+        let min_ts = ts / 60;
+        if ts < self.latest || min_ts > self.to + self.interval.div_ceil(60) as MinuteTimestamp {
+            return false;
+        }
+        if ts != self.latest {
+            self.failed_polls += 1;
+        }
+        self.to = min_ts;
+        self.reason = reason;
+        self.latest = ts;
+        true
+    }
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone, Copy, PartialEq)]
+pub struct DayUptime {
+    /// Minute timestamp of the start of the day in UTC
+    pub day: MinuteTimestamp,
+    /// Minutes where the source was polled successfully
+    pub available: u32,
+    /// Minutes where the source was polled, successfully or not
+    pub observed: u32,
+    /// `available / observed`, or `None` if nothing was observed
+    pub uptime: Option<f32>,
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{hour::GapReason, outage::Outage};
+
+    #[test]
+    pub fn extend_outage() {
+        let mut outage = Outage::new(600, GapReason::SourceUnreachable, 60);
+        assert!(outage.extend(600, GapReason::SourceUnreachable));
+        assert!(outage.extend(660, GapReason::SourceError));
+        assert!(outage.extend(660, GapReason::SourceError));
+        assert!(!outage.extend(780, GapReason::SourceError));
+        assert_eq!(
+            outage,
+            Outage {
+                from: 10,
+                to: 11,
+                reason: GapReason::SourceError,
+                failed_polls: 2,
+                interval: 60,
+                latest: 660,
+            }
+        );
+
+        let mut outage = Outage::new(600, GapReason::SourceUnreachable, 300);
+        assert!(outage.extend(900, GapReason::SourceUnreachable));
+        assert!(outage.extend(1200, GapReason::SourceUnreachable));
+        assert!(!outage.extend(1560, GapReason::SourceUnreachable));
+        assert_eq!((outage.from, outage.to, outage.failed_polls), (10, 20, 3));
+
+        let mut outage = Outage::new(600, GapReason::SourceUnreachable, 15);
+        assert!(outage.extend(615, GapReason::SourceUnreachable));
+        assert!(outage.extend(630, GapReason::SourceUnreachable));
+        assert_eq!((outage.from, outage.to, outage.failed_polls), (10, 10, 3));
+    }
+}
@@ -0,0 +1,188 @@
+use std::collections::HashMap;
+
+use rocket::FromFormField;
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    hour::AbsRecord,
+    stats::Period,
+    utils::{Category, MinuteTimestamp},
+};
+
+#[derive(Deserialize, Serialize, Debug, Clone, Copy, PartialEq, Eq, Hash, FromFormField)]
+#[serde(rename_all = "snake_case")]
+pub enum PeakPeriod {
+    AllTime,
+    Day,
+    Week,
+    Month,
+}
+impl PeakPeriod {
+    pub const ALL: [Self; 4] = [Self::AllTime, Self::Day, Self::Week, Self::Month];
+
+    /// The start of the period containing `min_ts`, which is 0 for all time
+    #[must_use]
+    pub fn start_of(self, min_ts: MinuteTimestamp) -> MinuteTimestamp {
+        match self {
+            Self::AllTime => 0,
+            Self::Day => Period::Day.start_of(min_ts),
+            Self::Week => Period::Week.start_of(min_ts),
+            Self::Month => Period::Month.start_of(min_ts),
+        }
+    }
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct PeakId {
+    pub period: PeakPeriod,
+    /// Minute timestamp of the start of the period
+    pub start: MinuteTimestamp,
+}
+
+/// The highest number of players online at once, and the first minute it happened
+#[derive(Deserialize, Serialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Peak {
+    pub count: usize,
+    pub at: MinuteTimestamp,
+}
+
+/// A peak that was beaten by a new record
+#[derive(Deserialize, Serialize, Debug, Clone, PartialEq, Eq)]
+pub struct BrokenPeak {
+    pub id: PeakId,
+    /// `None` for all players
+    pub category: Option<Category>,
+    pub previous: Peak,
+    pub peak: Peak,
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone, PartialEq, Eq)]
+pub struct Peaks {
+    pub _id: PeakId,
+    pub all: Option<Peak>,
+    pub categories: HashMap<Category, Peak>,
+}
+impl Peaks {
+    #[must_use]
+    pub fn empty(_id: PeakId) -> Self {
+        Self {
+            _id,
+            all: None,
+            categories: HashMap::new(),
+        }
+    }
+
+    /// Updates the peaks with a record at `min_ts`.
+    /// Returns whether anything changed, and the peaks that were beaten.
+    pub fn update(
+        &mut self,
+        record: &AbsRecord,
+        min_ts: MinuteTimestamp,
+    ) -> (bool, Vec<BrokenPeak>) {
+        let mut changed = false;
+        let mut broken = vec![];
+        let id = self._id;
+        let mut update = |peak: &mut Option<Peak>, count: usize, category: Option<&Category>| {
+            if peak.is_some_and(|peak| peak.count >= count) {
+                return;
+            }
+            let new = Peak { count, at: min_ts };
+            changed = true;
+            if let Some(previous) = peak.replace(new) {
+                broken.push(BrokenPeak {
+                    id,
+                    category: category.cloned(),
+                    previous,
+                    peak: new,
+                });
+            }
+        };
+        update(&mut self.all, record.all.len(), None);
+        for (cat, players) in &record.categories {
+            let mut peak = self.categories.get(cat).copied();
+            update(&mut peak, players.len(), Some(cat));
+            if let Some(peak) = peak {
+                self.categories.insert(cat.to_owned(), peak);
+            }
+        }
+        (changed, broken)
+    }
+    /// Updates the peaks of every period containing `min_ts`, adding the ones missing from `peaks`
+    pub fn update_all(
+        peaks: &mut HashMap<PeakId, Self>,
+        record: &AbsRecord,
+        min_ts: MinuteTimestamp,
+    ) {
+        for period in PeakPeriod::ALL {
+            let id = PeakId {
+                period,
+                start: period.start_of(min_ts),
+            };
+            peaks
+                .entry(id)
+                .or_insert_with(|| Self::empty(id))
+                .update(record, min_ts);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::{HashMap, HashSet};
+
+    use crate::{
+        hour::AbsRecord,
+        peaks::{Peak, PeakId, PeakPeriod, Peaks},
+    };
+
+    #[test]
+    pub fn update_peaks() {
+        let mut peaks = Peaks::empty(PeakId {
+            period: PeakPeriod::AllTime,
+            start: 0,
+        });
+        let record = |all: &[usize], staff: &[usize]| AbsRecord {
+            all: all.iter().copied().collect(),
+            categories: HashMap::from([(
+                "staff".into(),
+                staff.iter().copied().collect::<HashSet<_>>(),
+            )]),
+        };
+        let (changed, broken) = peaks.update(&record(&[0, 1], &[0]), 10);
+        assert!(changed);
+        assert!(broken.is_empty());
+        let (changed, broken) = peaks.update(&record(&[0, 1], &[]), 11);
+        assert!(!changed);
+        assert!(broken.is_empty());
+        let (changed, broken) = peaks.update(&record(&[0, 1, 2], &[0]), 12);
+        assert!(changed);
+        assert_eq!(broken.len(), 1);
+        assert_eq!(broken[0].category, None);
+        assert_eq!(broken[0].previous, Peak { count: 2, at: 10 });
+        assert_eq!(peaks.all, Some(Peak { count: 3, at: 12 }));
+        assert_eq!(peaks.categories["staff"], Peak { count: 1, at: 10 });
+
+        let day = 60 * 24;
+        let mut history = HashMap::new();
+        Peaks::update_all(&mut history, &record(&[0], &[]), 10);
+        Peaks::update_all(&mut history, &record(&[0, 1], &[]), day + 10);
+        let all_time = PeakId {
+            period: PeakPeriod::AllTime,
+            start: 0,
+        };
+        let first_day = PeakId {
+            period: PeakPeriod::Day,
+            start: 0,
+        };
+        // 1970-01-01 and 01-02 are in the same week and month
+        assert_eq!(history.len(), 5);
+        assert_eq!(
+            history[&all_time].all,
+            Some(Peak {
+                count: 2,
+                at: day + 10
+            })
+        );
+        assert_eq!(history[&first_day].all, Some(Peak { count: 1, at: 10 }));
+    }
+}
@@ -0,0 +1,96 @@
+use std::{
+    path::{Path, PathBuf},
+    sync::Arc,
+    time::{Duration, SystemTime},
+};
+
+use tokio::sync::RwLock;
+use tracing::{debug, error, info, warn};
+
+use crate::{config::Config, tracker::StatusTracker, utils::get_second_timestamp};
+
+const FILE_POLL_INTERVAL: Duration = Duration::from_secs(5);
+
+fn modified(path: &Path) -> Option<SystemTime> {
+    std::fs::metadata(path).and_then(|a| a.modified()).ok()
+}
+
+#[tracing::instrument(skip(tracker))]
+pub async fn reload_config(path: &Path, tracker: &RwLock<StatusTracker>) {
+    let mut config = match Config::load(path) {
+        Ok(config) => config,
+        Err(e) => {
+            error!("Rejecting config reload, keeping old config: {e}");
+            return;
+        }
+    };
+    let mut tracker = tracker.write().await;
+    if config.mongodb_uri != tracker.config.mongodb_uri
+        || config.database_name != tracker.config.database_name
+    {
+        warn!("Database settings changed, these only take effect after a restart");
+    }
+    if config.no_write != tracker.config.no_write {
+        warn!("`no_write` changed, this only takes effect after a restart");
+    }
+    tracker.pending_interval = None;
+    if config.sample_interval != tracker.config.sample_interval {
+        // Changing it mid-hour would resample the hour being written
+        let at = (get_second_timestamp(SystemTime::now()) / 3600 + 1) * 3600;
+        info!(
+            from = tracker.config.sample_interval,
+            to = config.sample_interval,
+            at,
+            "`sample_interval` changed, this takes effect at the start of the next hour"
+        );
+        tracker.pending_interval = Some((config.sample_interval, at));
+        config.sample_interval = tracker.config.sample_interval;
+    }
+    tracker.config = config;
+    info!("Reloaded config");
+}
+
+pub fn spawn_config_watchers(
+    path: PathBuf,
+    tracker: &Arc<RwLock<StatusTracker>>,
+) -> Vec<tokio::task::JoinHandle<()>> {
+    let mut handles = vec![];
+
+    #[cfg(unix)]
+    {
+        use tokio::signal::unix::{signal, SignalKind};
+
+        let path = path.clone();
+        let tracker = Arc::clone(tracker);
+        handles.push(tokio::spawn(async move {
+            let mut sighup = match signal(SignalKind::hangup()) {
+                Ok(sighup) => sighup,
+                Err(e) => {
+                    error!("Unable to listen for SIGHUP: {e}");
+                    return;
+                }
+            };
+            while sighup.recv().await.is_some() {
+                info!("Received SIGHUP");
+                reload_config(&path, &tracker).await;
+            }
+        }));
+    }
+
+    let tracker = Arc::clone(tracker);
+    handles.push(tokio::spawn(async move {
+        let mut last_modified = modified(&path);
+        loop {
+            tokio::time::sleep(FILE_POLL_INTERVAL).await;
+            let now_modified = modified(&path);
+            if now_modified == last_modified {
+                continue;
+            }
+            debug!(path = %path.display(), "Config file changed");
+            last_modified = now_modified;
+            reload_config(&path, &tracker).await;
+        }
+    }));
+
+    handles
+}
@@ -0,0 +1,301 @@
+use std::collections::{HashMap, HashSet};
+
+use itertools::Itertools;
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    hour::{Hour, RollingAvgRecord, DEFAULT_RESOLUTION},
+    utils::{Category, MinuteTimestamp},
+};
+
+#[derive(Deserialize, Serialize, Debug, Clone, Copy, PartialEq, Eq, Hash)]
+#[serde(rename_all = "snake_case")]
+pub enum RollupPeriod {
+    Hour,
+    Day,
+}
+impl RollupPeriod {
+    #[must_use]
+    pub const fn minutes(self) -> u64 {
+        match self {
+            Self::Hour => 60,
+            Self::Day => 60 * 24,
+        }
+    }
+    #[must_use]
+    pub const fn start_of(self, min_ts: MinuteTimestamp) -> MinuteTimestamp {
+        min_ts / self.minutes() * self.minutes()
+    }
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct RollupId {
+    pub period: RollupPeriod,
+    /// Minute timestamp of the start of the period
+    pub start: MinuteTimestamp,
+}
+
+/// Statistics of the number of players online over a period
+#[derive(Deserialize, Serialize, Debug, Clone, Default, PartialEq)]
+pub struct SeriesStats {
+    pub mean: f32,
+    pub min: u32,
+    pub max: u32,
+    /// Indices into the name map of every player that was online during the period
+    pub players: HashSet<usize>,
+    /// Number of minutes with each number of players online, indexed by the number of players
+    #[serde(default)]
+    pub histogram: Vec<u32>,
+}
+impl SeriesStats {
+    #[must_use]
+    pub fn unique(&self) -> usize {
+        self.players.len()
+    }
+}
+
+/// Adds `minutes` minutes with `count` players online to a histogram
+fn add_to_histogram(histogram: &mut Vec<u32>, count: usize, minutes: u32) {
+    if histogram.len() <= count {
+        histogram.resize(count + 1, 0);
+    }
+    histogram[count] += minutes;
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone, PartialEq)]
+pub struct Rollup {
+    pub _id: RollupId,
+    /// Number of minutes in the period with a record
+    pub samples: u32,
+    pub all: SeriesStats,
+    pub categories: HashMap<Category, SeriesStats>,
+}
+impl Rollup {
+    #[must_use]
+    pub fn empty(_id: RollupId) -> Self {
+        Self {
+            _id,
+            samples: 0,
+            all: SeriesStats::default(),
+            categories: HashMap::new(),
+        }
+    }
+
+    #[must_use]
+    pub fn from_hour(hour: &Hour) -> Self {
+        let hour = hour.resample(DEFAULT_RESOLUTION);
+        let records = hour.records.iter().flatten().collect::<Vec<_>>();
+        let mut rollup = Self::empty(RollupId {
+            period: RollupPeriod::Hour,
+            start: u64::from(hour._id) * 60,
+        });
+        if records.is_empty() {
+            return rollup;
+        }
+        rollup.samples = records.len() as u32;
+
+        let stats = |sets: &[Option<&HashSet<usize>>]| {
+            let counts = sets
+                .iter()
+                .map(|a| a.map_or(0, HashSet::len) as u32)
+                .collect::<Vec<_>>();
+            let mut histogram = vec![];
+            for count in &counts {
+                add_to_histogram(&mut histogram, *count as usize, 1);
+            }
+            SeriesStats {
+                mean: counts.iter().sum::<u32>() as f32 / counts.len() as f32,
+                min: counts.iter().copied().min().unwrap_or_default(),
+                max: counts.iter().copied().max().unwrap_or_default(),
+                players: sets.iter().flatten().copied().flatten().copied().collect(),
+                histogram,
+            }
+        };
+        rollup.all = stats(&records.iter().map(|a| Some(&a.all)).collect::<Vec<_>>());
+        rollup.categories = records
+            .iter()
+            .flat_map(|a| a.categories.keys())
+            .unique()
+            .map(|cat| {
+                (
+                    cat.to_owned(),
+                    stats(
+                        &records
+                            .iter()
+                            .map(|a| a.categories.get(cat))
+                            .collect::<Vec<_>>(),
+                    ),
+                )
+            })
+            .collect();
+        rollup
+    }
+
+    /// The mean number of players over all the given rollups, weighted by how many samples each has
+    #[must_use]
+    pub fn mean_of(rollups: &[Self]) -> Option<RollingAvgRecord> {
+        let samples = rollups.iter().map(|a| a.samples).sum::<u32>();
+        if samples == 0 {
+            return None;
+        }
+        let samples = samples as f32;
+        let mut categories: HashMap<Category, f32> = HashMap::new();
+        for rollup in rollups {
+            for (cat, stats) in &rollup.categories {
+                *categories.entry(cat.to_owned()).or_default() +=
+                    stats.mean * rollup.samples as f32 / samples;
+            }
+        }
+        Some(RollingAvgRecord {
+            all: rollups
+                .iter()
+                .map(|a| a.all.mean * a.samples as f32)
+                .sum::<f32>()
+                / samples,
+            categories,
+        })
+    }
+
+    /// Whether the rollup was saved before histograms were kept, and has to be computed again
+    #[must_use]
+    pub const fn is_outdated(&self) -> bool {
+        self.samples != 0 && self.all.histogram.is_empty()
+    }
+
+    /// Combines rollups of shorter periods into one covering `_id`
+    #[must_use]
+    pub fn combine(_id: RollupId, rollups: &[Self]) -> Self {
+        let rollups = rollups
+            .iter()
+            .filter(|a| a.samples != 0)
+            .collect::<Vec<_>>();
+        let mut rollup = Self::empty(_id);
+        rollup.samples = rollups.iter().map(|a| a.samples).sum();
+        if rollup.samples == 0 {
+            return rollup;
+        }
+
+        let combine = |stats: &[(u32, Option<&SeriesStats>)]| SeriesStats {
+            mean: stats
+                .iter()
+                .map(|(samples, a)| a.map_or(0.0, |a| a.mean) * *samples as f32)
+                .sum::<f32>()
+                / rollup.samples as f32,
+            min: stats
+                .iter()
+                .map(|(_, a)| a.map_or(0, |a| a.min))
+                .min()
+                .unwrap_or_default(),
+            max: stats
+                .iter()
+                .map(|(_, a)| a.map_or(0, |a| a.max))
+                .max()
+                .unwrap_or_default(),
+            players: stats
+                .iter()
+                .filter_map(|(_, a)| *a)
+                .flat_map(|a| &a.players)
+                .copied()
+                .collect(),
+            histogram: stats.iter().fold(vec![], |mut histogram, (samples, a)| {
+                match a {
+                    Some(a) => {
+                        for (count, minutes) in a.histogram.iter().enumerate() {
+                            add_to_histogram(&mut histogram, count, *minutes);
+                        }
+                    }
+                    None => add_to_histogram(&mut histogram, 0, *samples),
+                }
+                histogram
+            }),
+        };
+        rollup.all = combine(
+            &rollups
+                .iter()
+                .map(|a| (a.samples, Some(&a.all)))
+                .collect::<Vec<_>>(),
+        );
+        rollup.categories = rollups
+            .iter()
+            .flat_map(|a| a.categories.keys())
+            .unique()
+            .map(|cat| {
+                (
+                    cat.to_owned(),
+                    combine(
+                        &rollups
+                            .iter()
+                            .map(|a| (a.samples, a.categories.get(cat)))
+                            .collect::<Vec<_>>(),
+                    ),
+                )
+            })
+            .collect();
+        rollup
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        collections::{HashMap, HashSet},
+        sync::Arc,
+    };
+
+    use crate::{
+        hour::{AbsRecord, BandRecord, Hour},
+        rollup::{Rollup, RollupId, RollupPeriod},
+    };
+
+    #[test]
+    pub fn rollup_hours() {
+        let mut h1 = Hour::new(0);
+        h1.records[0] = Some(Arc::new(AbsRecord {
+            all: HashSet::from([0, 1]),
+            categories: HashMap::from([("staff".into(), HashSet::from([0]))]),
+        }));
+        h1.records[1] = Some(Arc::new(AbsRecord {
+            all: HashSet::from([1, 2, 3, 4]),
+            categories: HashMap::default(),
+        }));
+        let mut h2 = Hour::new(1);
+        h2.records[0] = Some(Arc::new(AbsRecord {
+            all: HashSet::from([5]),
+            categories: HashMap::default(),
+        }));
+
+        let r1 = Rollup::from_hour(&h1);
+        assert_eq!(r1.samples, 2);
+        assert_eq!((r1.all.mean, r1.all.min, r1.all.max), (3.0, 2, 4));
+        assert_eq!(r1.all.unique(), 5);
+        let staff = &r1.categories["staff"];
+        assert_eq!((staff.mean, staff.min, staff.max), (0.5, 0, 1));
+        assert_eq!(r1.all.histogram, vec![0, 0, 1, 0, 1]);
+
+        let day = Rollup::combine(
+            RollupId {
+                period: RollupPeriod::Day,
+                start: 0,
+            },
+            &[r1, Rollup::from_hour(&h2)],
+        );
+        assert_eq!(day.samples, 3);
+        assert_eq!((day.all.mean, day.all.min, day.all.max), (7.0 / 3.0, 1, 4));
+        assert_eq!(day.all.unique(), 6);
+        let staff = &day.categories["staff"];
+        assert_eq!((staff.mean, staff.min, staff.max), (1.0 / 3.0, 0, 1));
+        assert_eq!(staff.histogram, vec![2, 1]);
+
+        let records = h1
+            .records
+            .iter()
+            .chain(&h2.records)
+            .flatten()
+            .map(AsRef::as_ref)
+            .collect::<Vec<_>>();
+        assert_eq!(
+            BandRecord::from_rollups(&[day], &[25, 90]),
+            BandRecord::new(&records, &[25, 90])
+        );
+    }
+}
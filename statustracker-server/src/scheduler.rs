@@ -0,0 +1,118 @@
+use std::{
+    sync::Arc,
+    time::{Duration, SystemTime},
+};
+
+use serde::{Deserialize, Serialize};
+use tokio::sync::RwLock;
+use tracing::{error, info, warn};
+
+use crate::{
+    hour::GapReason,
+    tracker::StatusTracker,
+    utils::{get_second_timestamp, SecondTimestamp},
+};
+
+const MAX_ATTEMPTS: u32 = 3;
+const RETRY_DELAY: Duration = Duration::from_secs(5);
+/// Retries are only started if at least this fraction of the interval is left
+const RETRY_MARGIN: f32 = 0.25;
+const MAX_POLL_TIMINGS: usize = 60 * 24;
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PollTiming {
+    /// Start of the interval that was polled
+    pub time: SecondTimestamp,
+    /// How long after the start of the interval the first attempt started
+    pub delay: Duration,
+    /// How long all attempts took in total
+    pub duration: Duration,
+    pub attempts: u32,
+    pub success: bool,
+}
+
+fn to_system_time(ts: SecondTimestamp) -> SystemTime {
+    SystemTime::UNIX_EPOCH + Duration::from_secs(ts)
+}
+
+async fn sleep_until(t: SystemTime) {
+    if let Ok(d) = t.duration_since(SystemTime::now()) {
+        tokio::time::sleep(d).await;
+    }
+}
+
+#[tracing::instrument(skip(tracker))]
+async fn poll(tracker: &RwLock<StatusTracker>, ts: SecondTimestamp, interval: u32) -> PollTiming {
+    let start = SystemTime::now();
+    let deadline = to_system_time(ts + u64::from(interval));
+    let margin = Duration::from_secs(u64::from(interval)).mul_f32(RETRY_MARGIN);
+    let mut attempts = 0;
+    let success = loop {
+        attempts += 1;
+        let result = tracker.write().await.run(ts).await;
+        match result {
+            Ok(()) => break true,
+            Err(e) => error!(attempts, "{e}"),
+        }
+        let out_of_time = deadline
+            .duration_since(SystemTime::now())
+            .map_or(true, |left| left < margin.max(RETRY_DELAY));
+        if attempts >= MAX_ATTEMPTS || out_of_time {
+            break false;
+        }
+        tokio::time::sleep(RETRY_DELAY).await;
+    };
+    PollTiming {
+        time: ts,
+        delay: start.duration_since(to_system_time(ts)).unwrap_or_default(),
+        duration: start.elapsed().unwrap_or_default(),
+        attempts,
+        success,
+    }
+}
+
+/// Polls at the start of every interval, as set by `sample_interval` in the config.
+/// Intervals that were missed, e.g. because a poll took too long, are skipped and marked as gaps.
+pub async fn run_scheduler(tracker: Arc<RwLock<StatusTracker>>) {
+    let mut last_polled: Option<SecondTimestamp> = None;
+    loop {
+        let interval = u64::from(tracker.read().await.config.sample_interval);
+        let now = get_second_timestamp(SystemTime::now()) / interval * interval;
+        let mut next = last_polled.map_or(now, |a| (a / interval + 1) * interval);
+        if let Some(at) = tracker.write().await.apply_pending_interval(next) {
+            // Start polling at the new interval from the start of the hour
+            last_polled = Some(at - 1);
+            continue;
+        }
+        if next > now {
+            sleep_until(to_system_time(next)).await;
+        } else if next < now {
+            warn!(from = next, to = now - 1, "Skipping missed intervals");
+            let result = tracker
+                .read()
+                .await
+                .database
+                .add_gaps(
+                    next,
+                    now - 1,
+                    GapReason::TrackerOffline,
+                    u32::try_from(interval).unwrap(),
+                )
+                .await;
+            if let Err(e) = result {
+                error!("{e}");
+            }
+            next = now;
+        }
+
+        let timing = poll(&tracker, next, u32::try_from(interval).unwrap()).await;
+        info!(?timing);
+        last_polled = Some(next);
+
+        let mut tracker = tracker.write().await;
+        if tracker.poll_timings.len() >= MAX_POLL_TIMINGS {
+            tracker.poll_timings.pop_front();
+        }
+        tracker.poll_timings.push_back(timing);
+    }
+}
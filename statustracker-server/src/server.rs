@@ -1,25 +1,49 @@
-use std::{io::Cursor, sync::Arc, time::Duration};
+use std::{
+    cmp::Reverse, collections::HashMap, io::Cursor, path::PathBuf, sync::Arc, time::SystemTime,
+};
 
+use chrono_tz::Tz;
 use color_eyre::{
     eyre::{eyre, Result},
     Report,
 };
+use futures::{stream, StreamExt};
+use itertools::Itertools;
 use mongodb::bson::doc;
 use rocket::{
     fairing::{Fairing, Info, Kind},
-    http::{uri::Host, Header, Status},
+    http::{uri::Host, ContentType, Header, Status},
     response,
-    response::{content, Redirect, Responder},
-    routes, Request, Response, State,
+    response::{
+        content,
+        stream::{Event, EventStream, TextStream},
+        Redirect, Responder,
+    },
+    routes, Request, Response, Shutdown, State,
 };
 use serde::Serialize;
-use tokio::{sync::RwLock, time::Instant};
-use tracing::{error, info};
+use smol_str::SmolStr;
+use tokio::sync::{broadcast::error::RecvError, RwLock};
+use tracing::{error, info, warn};
 use uuid::Uuid;
 
 use crate::{
-    hour::RollingAvgRecord, name_to_uuid::name_to_uuid, tracker::StatusTracker,
-    utils::MinuteTimestamp,
+    config::CategoryInfo,
+    database::STDatabase,
+    export::{check_resolution, ExportFormat, MinuteExport, PlayerColumn},
+    health::{Health, HealthReport},
+    hour::{BandRecord, Gap, RollingAvgRecord, DEFAULT_RESOLUTION},
+    metrics::gather,
+    name_to_uuid::{cached_name, name_to_uuid, try_uuid_to_name},
+    outage::{DayUptime, Outage},
+    peaks::{PeakPeriod, Peaks},
+    reload::spawn_config_watchers,
+    rollup::RollupPeriod,
+    scheduler::run_scheduler,
+    stats::{Cohort, Coverage, Heatmap, Period, Playtime, SessionSummary, UniqueCount},
+    tracker::StatusTracker,
+    utils::{get_minute_timestamp, Category, MinuteTimestamp, SecondTimestamp},
+    webhooks::spawn_webhooks,
 };
 
 #[derive(Debug)]
@@ -36,6 +60,48 @@ impl<'r, T: Serialize> Responder<'r, 'static> for CustomMsgPack<T> {
         content::RawMsgPack(buf).respond_to(req)
     }
 }
+/// Number of names looked up at once for a response
+const NAME_LOOKUPS: usize = 8;
+/// Most names looked up from the API for one response, the rest are only taken from the cache
+const MAX_REMOTE_NAME_LOOKUPS: usize = 20;
+
+/// Names of `uuids` from the online players and the cache,
+/// looking up at most [`MAX_REMOTE_NAME_LOOKUPS`] of the rest from the API
+async fn lookup_names(tracker: &RwLock<StatusTracker>, uuids: &[Uuid]) -> Vec<Option<SmolStr>> {
+    let online = tracker
+        .read()
+        .await
+        .online
+        .players
+        .values()
+        .filter_map(|a| Some((a.uuid, a.name.clone()?)))
+        .collect::<HashMap<_, _>>();
+    let mut names = Vec::with_capacity(uuids.len());
+    for uuid in uuids {
+        names.push(match online.get(uuid) {
+            Some(name) => Some(name.to_owned()),
+            None => cached_name(*uuid).await,
+        });
+    }
+    let missing = names
+        .iter()
+        .zip(uuids)
+        .enumerate()
+        .filter(|(_, (name, _))| name.is_none())
+        .map(|(i, (_, uuid))| (i, *uuid))
+        .take(MAX_REMOTE_NAME_LOOKUPS)
+        .collect::<Vec<_>>();
+    let found = stream::iter(missing)
+        .map(|(i, uuid)| async move { (i, try_uuid_to_name(uuid).await) })
+        .buffered(NAME_LOOKUPS)
+        .collect::<Vec<_>>()
+        .await;
+    for (i, name) in found {
+        names[i] = name;
+    }
+    names
+}
+
 #[derive(Debug)]
 struct CustomError(pub Status, pub Report);
 
@@ -76,55 +142,664 @@ impl Fairing for CORS {
     }
 }
 
-#[rocket::get("/?<from>&<to>&<range>")]
+/// A response that is only wrapped with extra detail when the client asks for it,
+/// so that existing clients keep receiving the plain form
+#[derive(Serialize, Debug)]
+#[serde(untagged)]
+enum Detailed<P, D> {
+    Plain(P),
+    Detailed(D),
+}
+
+#[derive(Serialize, Debug)]
+struct RangeDetails {
+    records: Vec<Option<RollingAvgRecord>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    gaps: Option<Vec<Gap>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    bands: Option<Vec<Option<BandRecord>>>,
+}
+
+#[rocket::get("/?<from>&<to>&<range>&<gaps>&<bands>&<percentiles>")]
 async fn range(
     tracker: &State<Arc<RwLock<StatusTracker>>>,
     from: MinuteTimestamp,
     to: MinuteTimestamp,
     range: u64,
-) -> Result<CustomMsgPack<Vec<Option<RollingAvgRecord>>>, CustomError> {
-    if to - from > 60 * 24 * 365 * 5 {
+    gaps: Option<bool>,
+    bands: Option<bool>,
+    percentiles: Vec<u8>,
+) -> Result<CustomMsgPack<Detailed<Vec<Option<RollingAvgRecord>>, RangeDetails>>, CustomError> {
+    check_range(from, to)?;
+    if percentiles.iter().any(|p| *p > 100) {
+        return Err(CustomError(
+            Status::BadRequest,
+            eyre!("Percentiles must be at most 100"),
+        ));
+    }
+    let tracker = tracker.read().await;
+    let records = tracker.database.get_rolling_avg(from, to, range).await?;
+    let (gaps, bands) = (gaps.unwrap_or_default(), bands.unwrap_or_default());
+    if !gaps && !bands {
+        return Ok(CustomMsgPack(Detailed::Plain(records)));
+    }
+    let gaps = if gaps {
+        Some(tracker.database.get_gaps(from, to).await?)
+    } else {
+        None
+    };
+    let bands = if bands {
+        Some(
+            tracker
+                .database
+                .get_bands(from, to, range, &percentiles)
+                .await?,
+        )
+    } else {
+        None
+    };
+    Ok(CustomMsgPack(Detailed::Detailed(RangeDetails {
+        records,
+        gaps,
+        bands,
+    })))
+}
+
+#[derive(Serialize, Debug)]
+struct PlayerDetails {
+    sessions: Vec<(MinuteTimestamp, MinuteTimestamp)>,
+    gaps: Vec<Gap>,
+}
+
+#[rocket::get("/player/<name>?<from>&<to>&<gaps>")]
+async fn player(
+    tracker: &State<Arc<RwLock<StatusTracker>>>,
+    name: &str,
+    from: MinuteTimestamp,
+    to: MinuteTimestamp,
+    gaps: Option<bool>,
+) -> Result<
+    CustomMsgPack<Detailed<Vec<(MinuteTimestamp, MinuteTimestamp)>, PlayerDetails>>,
+    CustomError,
+> {
+    check_range(from, to)?;
+    let tracker = tracker.read().await;
+    let uuid = name_to_uuid(name).await?.unwrap_or_default();
+    let sessions = if let Some((i, _)) = tracker
+        .name_map
+        .data
+        .iter()
+        .enumerate()
+        .find(|(_, a)| *a == uuid.as_bytes())
+    {
+        tracker.database.get_player_join_times(from, to, i).await?
+    } else {
+        Vec::new()
+    };
+    if !gaps.unwrap_or_default() {
+        return Ok(CustomMsgPack(Detailed::Plain(sessions)));
+    }
+    let gaps = tracker.database.get_gaps(from, to).await?;
+    Ok(CustomMsgPack(Detailed::Detailed(PlayerDetails {
+        sessions,
+        gaps,
+    })))
+}
+
+#[derive(Serialize, Debug)]
+struct OutagesResponse {
+    outages: Vec<Outage>,
+    uptime: Vec<DayUptime>,
+}
+
+#[rocket::get("/outages?<from>&<to>")]
+async fn outages(
+    tracker: &State<Arc<RwLock<StatusTracker>>>,
+    from: MinuteTimestamp,
+    to: MinuteTimestamp,
+) -> Result<CustomMsgPack<OutagesResponse>, CustomError> {
+    check_range(from, to)?;
+    let tracker = tracker.read().await;
+    Ok(CustomMsgPack(OutagesResponse {
+        outages: tracker.database.get_outages(from, to).await?,
+        uptime: tracker.database.get_uptime(from, to).await?,
+    }))
+}
+
+/// Longest range of minutes that can be requested at once
+const MAX_RANGE: MinuteTimestamp = 60 * 24 * 365 * 5;
+
+/// Checks that `from` is not after `to`, and that the range is not too long
+fn check_range(from: MinuteTimestamp, to: MinuteTimestamp) -> Result<(), CustomError> {
+    if to < from {
+        return Err(CustomError(
+            Status::BadRequest,
+            eyre!("`to` is before `from`"),
+        ));
+    }
+    if to - from > MAX_RANGE {
         return Err(CustomError(
             Status::BadRequest,
             eyre!("Duration is too long"),
         ));
+    }
+    Ok(())
+}
+
+/// Parses an IANA timezone name, defaulting to UTC
+fn parse_tz(tz: Option<&str>) -> Result<Tz, CustomError> {
+    tz.map_or(Ok(Tz::UTC), |tz| {
+        tz.parse::<Tz>()
+            .map_err(|e| CustomError(Status::BadRequest, eyre!("Invalid timezone: {e}")))
+    })
+}
+
+/// `tz` is an IANA timezone name and defaults to UTC
+#[rocket::get("/heatmap?<from>&<to>&<tz>&<peak>")]
+async fn heatmap(
+    tracker: &State<Arc<RwLock<StatusTracker>>>,
+    from: MinuteTimestamp,
+    to: MinuteTimestamp,
+    tz: Option<&str>,
+    peak: Option<bool>,
+) -> Result<CustomMsgPack<Heatmap>, CustomError> {
+    check_range(from, to)?;
+    let tz = parse_tz(tz)?;
+    let rollups = tracker
+        .read()
+        .await
+        .database
+        .get_rollups(RollupPeriod::Hour, from, to)
+        .await?;
+    Ok(CustomMsgPack(Heatmap::new(
+        &rollups,
+        tz,
+        peak.unwrap_or_default(),
+    )))
+}
+
+/// `min` is the number of members of `category` that have to be online, and defaults to 1
+#[rocket::get("/coverage?<category>&<from>&<to>&<min>&<tz>")]
+async fn coverage(
+    tracker: &State<Arc<RwLock<StatusTracker>>>,
+    category: &str,
+    from: MinuteTimestamp,
+    to: MinuteTimestamp,
+    min: Option<usize>,
+    tz: Option<&str>,
+) -> Result<CustomMsgPack<Coverage>, CustomError> {
+    check_range(from, to)?;
+    let tz = parse_tz(tz)?;
+    let tracker = tracker.read().await;
+    if !tracker.config.categories.contains_key(category) {
+        return Err(CustomError(
+            Status::NotFound,
+            eyre!("Category {category} does not exist"),
+        ));
+    }
+    let mins = tracker.database.get_minutes(from, to).await?;
+    Ok(CustomMsgPack(Coverage::new(
+        from,
+        &mins,
+        category,
+        min.unwrap_or(1),
+        tz,
+    )))
+}
+
+/// The peaks of the current periods, or of every `period` starting from `from` to `to`
+#[rocket::get("/peaks?<period>&<from>&<to>")]
+async fn peaks(
+    tracker: &State<Arc<RwLock<StatusTracker>>>,
+    period: Option<PeakPeriod>,
+    from: Option<MinuteTimestamp>,
+    to: Option<MinuteTimestamp>,
+) -> Result<CustomMsgPack<Vec<Peaks>>, CustomError> {
+    let tracker = tracker.read().await;
+    let Some(period) = period else {
+        return Ok(CustomMsgPack(
+            PeakPeriod::ALL
+                .iter()
+                .filter_map(|a| tracker.peaks.get(a))
+                .cloned()
+                .collect(),
+        ));
     };
+    let to = to.unwrap_or_else(|| get_minute_timestamp(SystemTime::now()));
+    let from = from.unwrap_or_else(|| to.saturating_sub(60 * 24 * 365));
+    check_range(from, to)?;
+    Ok(CustomMsgPack(
+        tracker.database.find_peaks(period, from, to).await?,
+    ))
+}
+
+#[rocket::get("/unique?<from>&<to>&<period>")]
+async fn unique(
+    tracker: &State<Arc<RwLock<StatusTracker>>>,
+    from: MinuteTimestamp,
+    to: MinuteTimestamp,
+    period: Period,
+) -> Result<CustomMsgPack<Vec<UniqueCount>>, CustomError> {
+    check_range(from, to)?;
     let a = tracker
         .read()
         .await
         .database
-        .get_rolling_avg(from, to, range)
+        .get_unique_counts(period, from, to)
         .await?;
     Ok(CustomMsgPack(a))
 }
 
-#[rocket::get("/player/<name>?<from>&<to>")]
-async fn player(
+#[derive(Serialize, Debug)]
+struct LeaderboardEntry {
+    uuid: String,
+    /// `None` if the name is not known yet, see [`lookup_names`]
+    name: Option<SmolStr>,
+    #[serde(flatten)]
+    playtime: Playtime,
+}
+
+#[rocket::get("/leaderboard?<from>&<to>&<category>&<limit>")]
+async fn leaderboard(
+    tracker: &State<Arc<RwLock<StatusTracker>>>,
+    from: MinuteTimestamp,
+    to: MinuteTimestamp,
+    category: Option<&str>,
+    limit: Option<usize>,
+) -> Result<CustomMsgPack<Vec<LeaderboardEntry>>, CustomError> {
+    check_range(from, to)?;
+    let limit = limit.unwrap_or(10).min(1000);
+    let ranked = {
+        let tracker = tracker.read().await;
+        let playtimes = tracker.database.get_playtimes(from, to, category).await?;
+        playtimes
+            .into_iter()
+            .sorted_by_key(|(i, playtime)| (Reverse(playtime.minutes), *i))
+            .take(limit)
+            .filter_map(|(i, playtime)| {
+                Some((Uuid::from_bytes(*tracker.name_map.data.get(i)?), playtime))
+            })
+            .collect::<Vec<_>>()
+    };
+    let names = lookup_names(tracker, &ranked.iter().map(|(uuid, _)| *uuid).collect_vec()).await;
+    Ok(CustomMsgPack(
+        ranked
+            .into_iter()
+            .zip(names)
+            .map(|((uuid, playtime), name)| LeaderboardEntry {
+                uuid: uuid.to_string(),
+                name,
+                playtime,
+            })
+            .collect(),
+    ))
+}
+
+#[derive(Serialize, Debug)]
+struct PlayerSummary {
+    uuid: String,
+    #[serde(flatten)]
+    sessions: SessionSummary,
+    categories: Vec<Category>,
+}
+
+/// `tz` is an IANA timezone name for the most active hour and defaults to UTC
+#[rocket::get("/player/<name>/summary?<from>&<to>&<tz>")]
+async fn player_summary(
     tracker: &State<Arc<RwLock<StatusTracker>>>,
     name: &str,
     from: MinuteTimestamp,
     to: MinuteTimestamp,
+    tz: Option<&str>,
+) -> Result<CustomMsgPack<PlayerSummary>, CustomError> {
+    check_range(from, to)?;
+    let tz = parse_tz(tz)?;
+    let tracker = tracker.read().await;
+    let uuid = name_to_uuid(name)
+        .await?
+        .ok_or_else(|| CustomError(Status::NotFound, eyre!("Player {name} does not exist")))?;
+    let sessions = if let Some(i) = tracker
+        .name_map
+        .data
+        .iter()
+        .position(|a| a == uuid.as_bytes())
+    {
+        tracker.database.get_player_join_times(from, to, i).await?
+    } else {
+        Vec::new()
+    };
+    Ok(CustomMsgPack(PlayerSummary {
+        uuid: uuid.to_string(),
+        sessions: SessionSummary::new(&sessions, tz),
+        categories: tracker
+            .config
+            .categories
+            .iter()
+            .filter(|(_, uuids)| uuids.contains(&uuid))
+            .map(|(cat, _)| cat.to_owned())
+            .sorted()
+            .collect(),
+    }))
+}
+
+#[derive(Serialize, Debug)]
+struct CopresenceEntry {
+    uuid: String,
+    /// `None` if the name is not known yet, see [`lookup_names`]
+    name: Option<SmolStr>,
+    minutes: u64,
+}
+
+#[rocket::get("/copresence/<name>?<from>&<to>&<limit>")]
+async fn copresence(
+    tracker: &State<Arc<RwLock<StatusTracker>>>,
+    name: &str,
+    from: MinuteTimestamp,
+    to: MinuteTimestamp,
+    limit: Option<usize>,
+) -> Result<CustomMsgPack<Vec<CopresenceEntry>>, CustomError> {
+    check_range(from, to)?;
+    let limit = limit.unwrap_or(10).min(1000);
+    let uuid = name_to_uuid(name).await?.unwrap_or_default();
+    let ranked = {
+        let tracker = tracker.read().await;
+        let Some(player) = tracker.name_map.index_of(&uuid) else {
+            return Ok(CustomMsgPack(Vec::new()));
+        };
+        let minutes = tracker.database.get_copresence(from, to, player).await?;
+        minutes
+            .into_iter()
+            .sorted_by_key(|(i, minutes)| (Reverse(*minutes), *i))
+            .take(limit)
+            .filter_map(|(i, minutes)| {
+                Some((Uuid::from_bytes(*tracker.name_map.data.get(i)?), minutes))
+            })
+            .collect::<Vec<_>>()
+    };
+    let names = lookup_names(tracker, &ranked.iter().map(|(uuid, _)| *uuid).collect_vec()).await;
+    Ok(CustomMsgPack(
+        ranked
+            .into_iter()
+            .zip(names)
+            .map(|((uuid, minutes), name)| CopresenceEntry {
+                uuid: uuid.to_string(),
+                name,
+                minutes,
+            })
+            .collect(),
+    ))
+}
+
+#[rocket::get("/copresence/<name>/<other>?<from>&<to>")]
+async fn copresence_pair(
+    tracker: &State<Arc<RwLock<StatusTracker>>>,
+    name: &str,
+    other: &str,
+    from: MinuteTimestamp,
+    to: MinuteTimestamp,
 ) -> Result<CustomMsgPack<Vec<(MinuteTimestamp, MinuteTimestamp)>>, CustomError> {
-    if to - from > 60 * 24 * 365 * 5 {
+    check_range(from, to)?;
+    let uuid = name_to_uuid(name).await?.unwrap_or_default();
+    let other = name_to_uuid(other).await?.unwrap_or_default();
+    let tracker = tracker.read().await;
+    let (Some(player), Some(other)) = (
+        tracker.name_map.index_of(&uuid),
+        tracker.name_map.index_of(&other),
+    ) else {
+        return Ok(CustomMsgPack(Vec::new()));
+    };
+    Ok(CustomMsgPack(
+        tracker
+            .database
+            .get_shared_times(from, to, player, other)
+            .await?,
+    ))
+}
+
+#[derive(Serialize, Debug)]
+struct OnlinePlayer {
+    uuid: String,
+    name: Option<SmolStr>,
+    categories: Vec<Category>,
+    since: MinuteTimestamp,
+}
+
+#[derive(Serialize, Debug)]
+struct OnlineResponse {
+    /// Second timestamp of the latest poll
+    updated: Option<SecondTimestamp>,
+    players: Vec<OnlinePlayer>,
+}
+
+#[rocket::get("/online")]
+async fn online(tracker: &State<Arc<RwLock<StatusTracker>>>) -> CustomMsgPack<OnlineResponse> {
+    let tracker = tracker.read().await;
+    CustomMsgPack(OnlineResponse {
+        updated: tracker.online.updated,
+        players: tracker
+            .online
+            .players
+            .values()
+            .sorted_by_key(|a| (a.since, a.name.clone()))
+            .map(|a| OnlinePlayer {
+                uuid: a.uuid.to_string(),
+                name: a.name.clone(),
+                categories: tracker
+                    .config
+                    .categories
+                    .iter()
+                    .filter(|(_, uuids)| uuids.contains(&a.uuid))
+                    .map(|(cat, _)| cat.to_owned())
+                    .sorted()
+                    .collect(),
+                since: a.since,
+            })
+            .collect(),
+    })
+}
+
+/// Server-sent events of everything sent to [`StatusTracker::events`], as JSON
+#[rocket::get("/events")]
+async fn events(
+    tracker: &State<Arc<RwLock<StatusTracker>>>,
+    mut shutdown: Shutdown,
+) -> EventStream![] {
+    let mut events = tracker.read().await.events.subscribe();
+    EventStream! {
+        loop {
+            let event = tokio::select! {
+                event = events.recv() => match event {
+                    Ok(event) => event,
+                    Err(RecvError::Lagged(skipped)) => {
+                        warn!(skipped, "Event subscriber lagged behind");
+                        continue;
+                    }
+                    Err(RecvError::Closed) => break,
+                },
+                () = &mut shutdown => break,
+            };
+            match serde_json::to_string(&event) {
+                Ok(data) => yield Event::data(data).event(event.name()),
+                Err(e) => error!("{e}"),
+            }
+        }
+    }
+}
+
+#[derive(Serialize, Debug)]
+struct SeenResponse {
+    player: String,
+    uuid: Option<String>,
+    first_seen: Option<MinuteTimestamp>,
+    last_seen: Option<MinuteTimestamp>,
+}
+
+/// `player` can be given multiple times, as a name or a UUID
+#[rocket::get("/seen?<player>")]
+async fn seen(
+    tracker: &State<Arc<RwLock<StatusTracker>>>,
+    player: Vec<&str>,
+) -> Result<CustomMsgPack<Vec<SeenResponse>>, CustomError> {
+    let mut uuids = Vec::with_capacity(player.len());
+    for p in &player {
+        uuids.push(if let Ok(uuid) = p.parse::<Uuid>() {
+            Some(uuid)
+        } else {
+            name_to_uuid(p).await?
+        });
+    }
+    let tracker = tracker.read().await;
+    Ok(CustomMsgPack(
+        player
+            .into_iter()
+            .zip(uuids)
+            .map(|(player, uuid)| {
+                let (first_seen, last_seen) = uuid
+                    .and_then(|uuid| {
+                        tracker
+                            .name_map
+                            .data
+                            .iter()
+                            .position(|a| a == uuid.as_bytes())
+                    })
+                    .map(|i| tracker.seen.get(i))
+                    .unwrap_or_default();
+                SeenResponse {
+                    player: player.to_owned(),
+                    uuid: uuid.map(|a| a.to_string()),
+                    first_seen,
+                    last_seen,
+                }
+            })
+            .collect(),
+    ))
+}
+
+/// `offsets` can be given multiple times, and defaults to 1, 2 and 4 periods
+#[rocket::get("/cohorts?<from>&<to>&<period>&<offsets>")]
+async fn cohorts(
+    tracker: &State<Arc<RwLock<StatusTracker>>>,
+    from: MinuteTimestamp,
+    to: MinuteTimestamp,
+    period: Option<Period>,
+    offsets: Vec<u32>,
+) -> Result<CustomMsgPack<Vec<Cohort>>, CustomError> {
+    check_range(from, to)?;
+    if offsets.iter().any(|a| *a > 60) {
         return Err(CustomError(
             Status::BadRequest,
-            eyre!("Duration is too long"),
+            eyre!("Offsets must be at most 60"),
         ));
+    }
+    let period = period.unwrap_or(Period::Week);
+    let offsets = if offsets.is_empty() {
+        vec![1, 2, 4]
+    } else {
+        offsets
     };
-    let tracker = tracker.read().await;
-    let uuid = name_to_uuid(name).await?.unwrap_or_default();
-    let Some((i, _)) = tracker
-        .name_map
-        .data
+    let now = get_minute_timestamp(SystemTime::now());
+    let starts = period.starts(from, to);
+    let retained_starts = starts
         .iter()
-        .enumerate()
-        .find(|(_, a)| *a == uuid.as_bytes())
-    else {
-        return Ok(CustomMsgPack(Vec::new()));
+        .flat_map(|start| {
+            offsets
+                .iter()
+                .map(|offset| Cohort::offset_start(period, *start, *offset))
+        })
+        .filter(|start| *start <= now)
+        .unique()
+        .collect::<Vec<_>>();
+
+    let tracker = tracker.read().await;
+    let active = tracker
+        .database
+        .get_active_players(period, &retained_starts)
+        .await?
+        .into_iter()
+        .map(|a| (a.start, a.all))
+        .collect();
+    Ok(CustomMsgPack(Cohort::build(
+        period,
+        &starts,
+        &tracker.seen.first,
+        &active,
+        &offsets,
+    )))
+}
+
+fn health_response(report: &HealthReport) -> (Status, content::RawJson<String>) {
+    (
+        if report.ok {
+            Status::Ok
+        } else {
+            Status::ServiceUnavailable
+        },
+        content::RawJson(serde_json::to_string(report).unwrap_or_default()),
+    )
+}
+
+/// Fails if polls have stopped finishing
+#[rocket::get("/healthz")]
+fn healthz(health: &State<Health>) -> (Status, content::RawJson<String>) {
+    health_response(&health.liveness())
+}
+
+/// Fails if the database is unreachable, or no records have been written recently
+#[rocket::get("/readyz")]
+async fn readyz(health: &State<Health>) -> (Status, content::RawJson<String>) {
+    health_response(&health.readiness().await)
+}
+
+/// Every minute from `from` to `to` as CSV or NDJSON rows, streamed a day at a time.
+/// `resolution` is the number of seconds between rows, for hours sampled more often than every minute.
+#[rocket::get("/export?<from>&<to>&<format>&<players>&<resolution>")]
+async fn export(
+    tracker: &State<Arc<RwLock<StatusTracker>>>,
+    from: MinuteTimestamp,
+    to: MinuteTimestamp,
+    format: Option<ExportFormat>,
+    players: Option<PlayerColumn>,
+    resolution: Option<u32>,
+) -> Result<(ContentType, TextStream![String]), CustomError> {
+    check_range(from, to)?;
+    let resolution = resolution.unwrap_or(DEFAULT_RESOLUTION);
+    check_resolution(resolution).map_err(|e| CustomError(Status::BadRequest, e))?;
+    let format = format.unwrap_or(ExportFormat::Csv);
+    let (database, name_map, mut export) = {
+        let tracker = tracker.read().await;
+        (
+            STDatabase(tracker.database.0.clone()),
+            tracker.name_map.clone(),
+            MinuteExport::new(
+                format,
+                players.unwrap_or_default(),
+                tracker.config.categories.keys().cloned().collect(),
+                resolution,
+            ),
+        )
     };
-    let a = tracker.database.get_player_join_times(from, to, i).await?;
+    Ok((
+        format.content_type(),
+        TextStream! {
+            if let Some(header) = export.header() {
+                yield header;
+            }
+            for (from, to) in MinuteExport::chunks(from, to) {
+                match export.rows(&database, &name_map, from, to).await {
+                    Ok(rows) => yield rows,
+                    Err(e) => {
+                        error!("Stopping export: {e}");
+                        break;
+                    }
+                }
+            }
+        },
+    ))
+}
 
-    Ok(CustomMsgPack(a))
+#[rocket::get("/metrics")]
+fn metrics() -> Result<content::RawText<String>, CustomError> {
+    Ok(content::RawText(gather()?))
 }
 
 #[rocket::get("/name_map")]
@@ -139,6 +814,68 @@ async fn name_map(tracker: &State<Arc<RwLock<StatusTracker>>>) -> CustomMsgPack<
     )
 }
 
+#[derive(Serialize, Debug)]
+struct CategoryMember {
+    uuid: String,
+    /// `None` if the name could not be looked up
+    name: Option<SmolStr>,
+}
+
+#[derive(Serialize, Debug)]
+struct CategoryResponse {
+    name: Category,
+    #[serde(flatten)]
+    info: CategoryInfo,
+    members: Vec<CategoryMember>,
+}
+
+#[rocket::get("/categories")]
+async fn categories(
+    tracker: &State<Arc<RwLock<StatusTracker>>>,
+) -> Result<CustomMsgPack<Vec<CategoryResponse>>, CustomError> {
+    info!("Retrieving categories");
+    let categories = {
+        let config = &tracker.read().await.config;
+        config
+            .categories
+            .iter()
+            .map(|(cat, uuids)| {
+                (
+                    cat.to_owned(),
+                    config.category_info.get(cat).cloned().unwrap_or_default(),
+                    uuids.to_owned(),
+                )
+            })
+            .collect::<Vec<_>>()
+    };
+    let mut out = Vec::with_capacity(categories.len());
+    for (name, info, uuids) in categories {
+        let members = stream::iter(uuids)
+            .map(|uuid| async move {
+                CategoryMember {
+                    uuid: uuid.to_string(),
+                    name: try_uuid_to_name(uuid).await,
+                }
+            })
+            .buffered(NAME_LOOKUPS)
+            .collect::<Vec<_>>()
+            .await;
+        out.push(CategoryResponse {
+            name,
+            info,
+            members,
+        });
+    }
+    out.sort_by(|a, b| {
+        (a.info.order.is_none(), a.info.order, &a.name).cmp(&(
+            b.info.order.is_none(),
+            b.info.order,
+            &b.name,
+        ))
+    });
+    Ok(CustomMsgPack(out))
+}
+
 #[rocket::get("/uuid/<name>")]
 async fn uuid_route(name: &str) -> Result<CustomMsgPack<Option<String>>, CustomError> {
     Ok(CustomMsgPack(
@@ -160,36 +897,54 @@ async fn redirect_to_client(
     ))
 }
 
-pub async fn start_server(tracker: StatusTracker) -> Result<()> {
+pub async fn start_server(tracker: StatusTracker, config_path: PathBuf) -> Result<()> {
     let no_write = tracker.config.no_write;
+    let health = Health::new(tracker.database.0.clone(), no_write);
     let tracker = Arc::new(RwLock::new(tracker));
     let r = rocket::build()
         .mount(
             "/",
-            routes![range, name_map, player, uuid_route, redirect_to_client],
+            routes![
+                range,
+                name_map,
+                player,
+                player_summary,
+                seen,
+                outages,
+                unique,
+                leaderboard,
+                cohorts,
+                peaks,
+                heatmap,
+                copresence,
+                copresence_pair,
+                coverage,
+                online,
+                events,
+                metrics,
+                export,
+                healthz,
+                readyz,
+                categories,
+                uuid_route,
+                redirect_to_client
+            ],
         )
         .attach(CORS)
         .manage(Arc::clone(&tracker))
+        .manage(health)
         .ignite()
         .await?;
 
-    let h = (!no_write).then(|| {
-        tokio::spawn(async move {
-            loop {
-                let start = Instant::now();
-                let mut tracker = tracker.write().await;
-                let _ = tracker.run().await.map_err(|e| error!("{e}"));
-                drop(tracker);
-                let time_taken = Instant::now() - start;
-                info!(?time_taken);
-                tokio::time::sleep(Duration::from_secs(60) - time_taken).await;
-            }
-        })
-    });
-
+    let h = (!no_write).then(|| tokio::spawn(run_scheduler(Arc::clone(&tracker))));
+    let mut watchers = spawn_config_watchers(config_path, &tracker);
+    watchers.extend(spawn_webhooks(&tracker));
     let _ = r.launch().await?;
     if let Some(h) = h {
         h.abort();
     }
+    for watcher in watchers {
+        watcher.abort();
+    }
     Ok(())
 }
@@ -0,0 +1,620 @@
+use std::{
+    cmp::Reverse,
+    collections::{HashMap, HashSet, VecDeque},
+    sync::Arc,
+};
+
+use chrono::{DateTime, Datelike, Duration, Months, NaiveDate, TimeZone, Timelike, Utc};
+use chrono_tz::Tz;
+use itertools::Itertools;
+use rocket::FromFormField;
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    hour::AbsRecord,
+    rollup::Rollup,
+    utils::{Category, MinuteTimestamp},
+};
+
+/// A calendar period in UTC. Weeks start on Monday.
+#[derive(Deserialize, Serialize, Debug, Clone, Copy, PartialEq, Eq, Hash, FromFormField)]
+#[serde(rename_all = "snake_case")]
+pub enum Period {
+    Day,
+    Week,
+    Month,
+}
+impl Period {
+    fn date(min_ts: MinuteTimestamp) -> NaiveDate {
+        DateTime::<Utc>::from_timestamp(i64::try_from(min_ts * 60).unwrap_or(i64::MAX), 0)
+            .unwrap_or(DateTime::<Utc>::MAX_UTC)
+            .date_naive()
+    }
+    fn min_ts(date: NaiveDate) -> MinuteTimestamp {
+        u64::try_from(date.and_hms_opt(0, 0, 0).unwrap().and_utc().timestamp() / 60).unwrap_or(0)
+    }
+    #[must_use]
+    pub fn start_of(self, min_ts: MinuteTimestamp) -> MinuteTimestamp {
+        let date = Self::date(min_ts);
+        Self::min_ts(match self {
+            Self::Day => date,
+            Self::Week => date - Duration::days(i64::from(date.weekday().num_days_from_monday())),
+            Self::Month => date.with_day(1).unwrap(),
+        })
+    }
+    /// The start of the period after the one starting at `start`
+    #[must_use]
+    pub fn next(self, start: MinuteTimestamp) -> MinuteTimestamp {
+        let date = Self::date(start);
+        Self::min_ts(match self {
+            Self::Day => date + Duration::days(1),
+            Self::Week => date + Duration::weeks(1),
+            Self::Month => date + Months::new(1),
+        })
+    }
+    /// Starts of every period that overlaps with the minutes from `from` to `to`
+    #[must_use]
+    pub fn starts(self, from: MinuteTimestamp, to: MinuteTimestamp) -> Vec<MinuteTimestamp> {
+        let mut out = vec![];
+        let mut start = self.start_of(from);
+        while start <= to {
+            out.push(start);
+            start = self.next(start);
+        }
+        out
+    }
+}
+
+/// Every player online during a period, by name map index
+#[derive(Deserialize, Serialize, Debug, Clone, Default, PartialEq, Eq)]
+pub struct ActivePlayers {
+    pub start: MinuteTimestamp,
+    pub all: HashSet<usize>,
+    pub categories: HashMap<Category, HashSet<usize>>,
+}
+
+/// Number of distinct players online during a period
+#[derive(Deserialize, Serialize, Debug, Clone, PartialEq, Eq)]
+pub struct UniqueCount {
+    pub start: MinuteTimestamp,
+    pub all: usize,
+    pub categories: HashMap<Category, usize>,
+}
+
+/// Unique counts of periods that are over, evicting the oldest entries past `capacity`
+#[derive(Debug, Default)]
+pub struct UniqueCache {
+    pub capacity: usize,
+    counts: HashMap<(Period, MinuteTimestamp), UniqueCount>,
+    order: VecDeque<(Period, MinuteTimestamp)>,
+}
+impl UniqueCache {
+    #[must_use]
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            capacity,
+            ..Self::default()
+        }
+    }
+    #[must_use]
+    pub fn get(&self, period: Period, start: MinuteTimestamp) -> Option<&UniqueCount> {
+        self.counts.get(&(period, start))
+    }
+    pub fn insert(&mut self, period: Period, count: UniqueCount) {
+        let key = (period, count.start);
+        if self.counts.insert(key, count).is_none() {
+            self.order.push_back(key);
+        }
+        while self.order.len() > self.capacity {
+            if let Some(key) = self.order.pop_front() {
+                self.counts.remove(&key);
+            }
+        }
+    }
+    /// Removes the counts of periods that overlap with the minutes from `from` to `to`
+    pub fn invalidate(&mut self, from: MinuteTimestamp, to: MinuteTimestamp) {
+        self.counts
+            .retain(|(period, start), _| *start > to || period.next(*start) <= from);
+        let counts = &self.counts;
+        self.order.retain(|key| counts.contains_key(key));
+    }
+}
+
+/// Players first seen during a period, and how many of them came back in later periods
+#[derive(Deserialize, Serialize, Debug, Clone, PartialEq, Eq)]
+pub struct Cohort {
+    pub start: MinuteTimestamp,
+    /// `None` if the period did not start after the earliest player was first seen,
+    /// as every player online then would count as new
+    pub new_players: Option<usize>,
+    /// Number of new players online again the given number of periods later, keyed by that number.
+    /// Periods that have not started yet are left out.
+    pub retained: HashMap<u32, usize>,
+}
+impl Cohort {
+    /// The start of the period `offset` periods after the one starting at `start`
+    #[must_use]
+    pub fn offset_start(period: Period, start: MinuteTimestamp, offset: u32) -> MinuteTimestamp {
+        (0..offset).fold(start, |start, _| period.next(start))
+    }
+    /// `first_seen` is indexed by name map index, and `active` must contain every period that is retained into
+    #[must_use]
+    pub fn build(
+        period: Period,
+        starts: &[MinuteTimestamp],
+        first_seen: &[Option<MinuteTimestamp>],
+        active: &HashMap<MinuteTimestamp, HashSet<usize>>,
+        offsets: &[u32],
+    ) -> Vec<Self> {
+        let earliest = first_seen.iter().flatten().min().copied();
+        starts
+            .iter()
+            .map(|start| {
+                if earliest.is_none_or(|a| a >= *start) {
+                    return Self {
+                        start: *start,
+                        new_players: None,
+                        retained: HashMap::new(),
+                    };
+                }
+                let end = period.next(*start);
+                let new_players = first_seen
+                    .iter()
+                    .positions(|a| a.is_some_and(|a| a >= *start && a < end))
+                    .collect::<HashSet<_>>();
+                Self {
+                    start: *start,
+                    new_players: Some(new_players.len()),
+                    retained: offsets
+                        .iter()
+                        .filter_map(|offset| {
+                            let active =
+                                active.get(&Self::offset_start(period, *start, *offset))?;
+                            Some((*offset, new_players.intersection(active).count()))
+                        })
+                        .collect(),
+                }
+            })
+            .collect()
+    }
+}
+
+/// How long a player was online for within a range
+#[derive(Deserialize, Serialize, Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Playtime {
+    pub minutes: u64,
+    pub sessions: u32,
+}
+
+/// An hour of the week. `day` is 0 for Monday.
+#[derive(Deserialize, Serialize, Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct HourOfWeek {
+    pub day: u8,
+    pub hour: u8,
+}
+impl HourOfWeek {
+    /// The hour of the week in UTC of a minute timestamp
+    #[must_use]
+    pub const fn of(min_ts: MinuteTimestamp) -> Self {
+        let hours = min_ts / 60;
+        Self {
+            // 1970-01-01 was a Thursday
+            day: ((hours / 24 + 3) % 7) as u8,
+            hour: (hours % 24) as u8,
+        }
+    }
+    /// The hour of the week in `tz` of a minute timestamp
+    #[must_use]
+    pub fn in_tz(min_ts: MinuteTimestamp, tz: Tz) -> Self {
+        let time = tz.timestamp_opt(i64::try_from(min_ts * 60).unwrap_or(i64::MAX), 0);
+        time.single().map_or_else(
+            || Self::of(min_ts),
+            |time| Self {
+                day: time.weekday().num_days_from_monday() as u8,
+                hour: time.hour() as u8,
+            },
+        )
+    }
+}
+
+pub type Grid<T> = [[T; 24]; 7];
+
+/// Player counts by hour of the week, indexed by `[day][hour]` with Monday as day 0.
+/// Hours with no samples are `None`.
+#[derive(Deserialize, Serialize, Debug, Clone, Default, PartialEq)]
+pub struct HeatmapGrid {
+    pub mean: Grid<Option<f32>>,
+    pub peak: Option<Grid<Option<u32>>>,
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone, Default, PartialEq)]
+pub struct Heatmap {
+    pub all: HeatmapGrid,
+    pub categories: HashMap<Category, HeatmapGrid>,
+}
+impl Heatmap {
+    /// Builds the heatmap from hour rollups, placing each one at the local hour it starts in.
+    /// In timezones that are not offset by whole hours, hours are split at the UTC hour instead.
+    #[must_use]
+    pub fn new(rollups: &[Rollup], tz: Tz, peak: bool) -> Self {
+        let mut samples: Grid<u32> = Grid::default();
+        let mut sums: HashMap<Option<&Category>, (Grid<f32>, Grid<u32>)> = HashMap::new();
+        for rollup in rollups.iter().filter(|a| a.samples != 0) {
+            let HourOfWeek { day, hour } = HourOfWeek::in_tz(rollup._id.start, tz);
+            let (day, hour) = (usize::from(day), usize::from(hour));
+            samples[day][hour] += rollup.samples;
+            let series = std::iter::once((None, &rollup.all))
+                .chain(rollup.categories.iter().map(|(cat, a)| (Some(cat), a)));
+            for (cat, stats) in series {
+                let (sum, max) = sums.entry(cat).or_default();
+                sum[day][hour] += stats.mean * rollup.samples as f32;
+                max[day][hour] = max[day][hour].max(stats.max);
+            }
+        }
+        let grid = |(sum, max): &(Grid<f32>, Grid<u32>)| HeatmapGrid {
+            mean: std::array::from_fn(|day| {
+                std::array::from_fn(|hour| {
+                    (samples[day][hour] != 0).then(|| sum[day][hour] / samples[day][hour] as f32)
+                })
+            }),
+            peak: peak.then(|| {
+                std::array::from_fn(|day| {
+                    std::array::from_fn(|hour| (samples[day][hour] != 0).then_some(max[day][hour]))
+                })
+            }),
+        };
+        Self {
+            all: sums
+                .get(&None)
+                .map_or_else(|| grid(&Default::default()), grid),
+            categories: sums
+                .iter()
+                .filter_map(|(cat, a)| Some(((*cat)?.to_owned(), grid(a))))
+                .collect(),
+        }
+    }
+}
+
+/// Inclusive `(start, end)` runs of the minutes starting from `from` whose records match `pred`.
+/// Minutes without a record end a run.
+pub fn intervals<F: Fn(&AbsRecord) -> bool>(
+    from: MinuteTimestamp,
+    records: &[Option<Arc<AbsRecord>>],
+    pred: F,
+) -> Vec<(MinuteTimestamp, MinuteTimestamp)> {
+    let mut out = vec![];
+    let mut start: Option<MinuteTimestamp> = None;
+    for (min, record) in (from..).zip(records) {
+        if record.as_deref().is_some_and(&pred) {
+            start.get_or_insert(min);
+        } else if let Some(in_start) = start.take() {
+            out.push((in_start, min - 1));
+        }
+    }
+    if let Some(in_start) = start {
+        out.push((in_start, from + records.len() as u64 - 1));
+    }
+    out
+}
+
+/// How often at least a number of members of a category were online
+#[derive(Deserialize, Serialize, Debug, Clone, PartialEq)]
+pub struct Coverage {
+    /// Minutes with a record
+    pub tracked: u64,
+    /// Tracked minutes with enough members online
+    pub covered: u64,
+    /// `covered / tracked`, or `None` if nothing was tracked
+    pub coverage: Option<f32>,
+    /// Inclusive runs of tracked minutes without enough members online
+    pub uncovered: Vec<(MinuteTimestamp, MinuteTimestamp)>,
+    /// Coverage by hour of the week, indexed like [`HeatmapGrid`]
+    pub by_hour: Grid<Option<f32>>,
+}
+impl Coverage {
+    /// `records` are the minutes starting from `from`
+    #[must_use]
+    pub fn new(
+        from: MinuteTimestamp,
+        records: &[Option<Arc<AbsRecord>>],
+        category: &str,
+        min: usize,
+        tz: Tz,
+    ) -> Self {
+        let is_covered = |a: &AbsRecord| a.categories.get(category).map_or(0, HashSet::len) >= min;
+        let mut tracked: Grid<u64> = Grid::default();
+        let mut covered: Grid<u64> = Grid::default();
+        for (min_ts, record) in (from..).zip(records) {
+            let Some(record) = record else {
+                continue;
+            };
+            let HourOfWeek { day, hour } = HourOfWeek::in_tz(min_ts, tz);
+            let (day, hour) = (usize::from(day), usize::from(hour));
+            tracked[day][hour] += 1;
+            if is_covered(record) {
+                covered[day][hour] += 1;
+            }
+        }
+        let total = |grid: &Grid<u64>| grid.iter().flatten().sum::<u64>();
+        let ratio =
+            |covered: u64, tracked: u64| (tracked != 0).then(|| covered as f32 / tracked as f32);
+        Self {
+            tracked: total(&tracked),
+            covered: total(&covered),
+            coverage: ratio(total(&covered), total(&tracked)),
+            uncovered: intervals(from, records, |a| !is_covered(a)),
+            by_hour: std::array::from_fn(|day| {
+                std::array::from_fn(|hour| ratio(covered[day][hour], tracked[day][hour]))
+            }),
+        }
+    }
+}
+
+/// Minutes every other player was online at the same time as `player`
+#[must_use]
+pub fn copresence(records: &[Option<Arc<AbsRecord>>], player: usize) -> HashMap<usize, u64> {
+    let mut out: HashMap<usize, u64> = HashMap::new();
+    for record in records.iter().flatten() {
+        if !record.all.contains(&player) {
+            continue;
+        }
+        for other in record.all.iter().filter(|a| **a != player) {
+            *out.entry(*other).or_default() += 1;
+        }
+    }
+    out
+}
+
+/// Statistics of a player's sessions within a range, with times in minutes
+#[derive(Deserialize, Serialize, Debug, Clone, Default, PartialEq)]
+pub struct SessionSummary {
+    pub total_playtime: u64,
+    pub sessions: usize,
+    pub average_session: f32,
+    pub longest_session: u64,
+    pub first_seen: Option<MinuteTimestamp>,
+    pub last_seen: Option<MinuteTimestamp>,
+    /// The hour of the week in the requested timezone with the most playtime
+    pub most_active_hour: Option<HourOfWeek>,
+}
+impl SessionSummary {
+    /// `sessions` are inclusive `(start, end)` tuples, sorted, as returned by `get_player_join_times`.
+    /// The most active hour is taken in `tz`
+    #[must_use]
+    pub fn new(sessions: &[(MinuteTimestamp, MinuteTimestamp)], tz: Tz) -> Self {
+        let lengths = sessions
+            .iter()
+            .map(|(start, end)| end - start + 1)
+            .collect::<Vec<_>>();
+        let total_playtime = lengths.iter().sum::<u64>();
+        let mut hours: HashMap<HourOfWeek, u64> = HashMap::new();
+        for (start, end) in sessions {
+            for min_ts in *start..=*end {
+                *hours.entry(HourOfWeek::in_tz(min_ts, tz)).or_default() += 1;
+            }
+        }
+        Self {
+            total_playtime,
+            sessions: sessions.len(),
+            average_session: if sessions.is_empty() {
+                0.0
+            } else {
+                total_playtime as f32 / sessions.len() as f32
+            },
+            longest_session: lengths.iter().copied().max().unwrap_or_default(),
+            first_seen: sessions.first().map(|(start, _)| *start),
+            last_seen: sessions.last().map(|(_, end)| *end),
+            most_active_hour: hours
+                .into_iter()
+                .max_by_key(|(hour, minutes)| (*minutes, Reverse((hour.day, hour.hour))))
+                .map(|(hour, _)| hour),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        collections::{HashMap, HashSet},
+        sync::Arc,
+    };
+
+    use chrono_tz::Tz;
+
+    use crate::{
+        hour::AbsRecord,
+        rollup::{Rollup, RollupId, RollupPeriod, SeriesStats},
+        stats::{
+            copresence, intervals, Cohort, Coverage, Heatmap, HourOfWeek, Period, SessionSummary,
+            UniqueCache, UniqueCount,
+        },
+    };
+
+    #[test]
+    pub fn period_starts() {
+        // 2024-02-14 12:34 UTC, a Wednesday
+        let t = 1_707_914_040 / 60;
+        assert_eq!(Period::Day.start_of(t), 1_707_868_800 / 60);
+        assert_eq!(Period::Week.start_of(t), 1_707_696_000 / 60);
+        assert_eq!(Period::Month.start_of(t), 1_706_745_600 / 60);
+        assert_eq!(
+            Period::Month.next(Period::Month.start_of(t)),
+            1_709_251_200 / 60
+        );
+        assert_eq!(
+            Period::Week.starts(t, t + 60 * 24 * 7),
+            vec![1_707_696_000 / 60, 1_708_300_800 / 60]
+        );
+    }
+    #[test]
+    pub fn summarise_sessions() {
+        // 2024-02-14 12:00 UTC, a Wednesday
+        let t = 1_707_912_000 / 60;
+        assert_eq!(HourOfWeek::of(t), HourOfWeek { day: 2, hour: 12 });
+        let summary = SessionSummary::new(&[(t, t + 9), (t + 60, t + 89)], Tz::UTC);
+        assert_eq!(
+            summary,
+            SessionSummary {
+                total_playtime: 40,
+                sessions: 2,
+                average_session: 20.0,
+                longest_session: 30,
+                first_seen: Some(t),
+                last_seen: Some(t + 89),
+                most_active_hour: Some(HourOfWeek { day: 2, hour: 13 }),
+            }
+        );
+        // 13:00 UTC is 21:00 in Singapore
+        assert_eq!(
+            SessionSummary::new(&[(t, t + 9), (t + 60, t + 89)], Tz::Asia__Singapore)
+                .most_active_hour,
+            Some(HourOfWeek { day: 2, hour: 21 })
+        );
+        assert_eq!(SessionSummary::new(&[], Tz::UTC), SessionSummary::default());
+    }
+    #[test]
+    pub fn build_cohorts() {
+        let day = 60 * 24;
+        let first_seen = [Some(0), Some(10), Some(day), None, Some(day + 5)];
+        let active = HashMap::from([
+            (day, HashSet::from([0, 2, 4])),
+            (day * 2, HashSet::from([1, 4])),
+        ]);
+        let cohorts = Cohort::build(Period::Day, &[0, day], &first_seen, &active, &[1, 2]);
+        assert_eq!(
+            cohorts,
+            vec![
+                Cohort {
+                    start: 0,
+                    new_players: None,
+                    retained: HashMap::new(),
+                },
+                Cohort {
+                    start: day,
+                    new_players: Some(2),
+                    retained: HashMap::from([(1, 1)]),
+                },
+            ]
+        );
+    }
+    #[test]
+    pub fn build_heatmap() {
+        // 2024-02-12 00:00 UTC, a Monday
+        let t = 1_707_696_000 / 60;
+        let rollup = |start, samples, mean, max| Rollup {
+            _id: RollupId {
+                period: RollupPeriod::Hour,
+                start,
+            },
+            samples,
+            all: SeriesStats {
+                mean,
+                min: 0,
+                max,
+                players: HashSet::new(),
+                histogram: vec![],
+            },
+            categories: HashMap::from([(
+                "staff".into(),
+                SeriesStats {
+                    mean: 1.0,
+                    min: 1,
+                    max: 1,
+                    players: HashSet::new(),
+                    histogram: vec![],
+                },
+            )]),
+        };
+        let rollups = [rollup(t, 60, 2.0, 3), rollup(t + 60 * 24 * 7, 30, 5.0, 6)];
+        let heatmap = Heatmap::new(&rollups, Tz::UTC, true);
+        assert_eq!(heatmap.all.mean[0][0], Some(3.0));
+        assert_eq!(heatmap.all.peak.unwrap()[0][0], Some(6));
+        assert_eq!(heatmap.all.mean[0][1], None);
+        assert_eq!(heatmap.categories["staff"].mean[0][0], Some(1.0));
+
+        let heatmap = Heatmap::new(&rollups, Tz::America__New_York, false);
+        assert_eq!(heatmap.all.mean[6][19], Some(3.0));
+        assert_eq!(heatmap.all.peak, None);
+        assert_eq!(
+            HourOfWeek::in_tz(t, Tz::Europe__Berlin),
+            HourOfWeek { day: 0, hour: 1 }
+        );
+    }
+    #[test]
+    pub fn shared_intervals() {
+        let record = |all: &[usize]| {
+            Some(Arc::new(AbsRecord {
+                all: all.iter().copied().collect(),
+                categories: HashMap::new(),
+            }))
+        };
+        let records = [
+            record(&[0, 1]),
+            record(&[0, 1, 2]),
+            None,
+            record(&[0, 1]),
+            record(&[0]),
+            record(&[0, 1, 2]),
+        ];
+        assert_eq!(
+            intervals(10, &records, |a| a.all.contains(&0) && a.all.contains(&1)),
+            vec![(10, 11), (13, 13), (15, 15)]
+        );
+        assert_eq!(
+            intervals(10, &records, |a| a.all.contains(&0)),
+            vec![(10, 11), (13, 15)]
+        );
+        assert_eq!(copresence(&records, 0), HashMap::from([(1, 4), (2, 2)]));
+        assert_eq!(copresence(&records, 3), HashMap::new());
+    }
+    #[test]
+    pub fn staff_coverage() {
+        // 2024-02-12 00:00 UTC, a Monday
+        let t = 1_707_696_000 / 60;
+        let record = |staff: &[usize]| {
+            Some(Arc::new(AbsRecord {
+                all: staff.iter().copied().collect(),
+                categories: HashMap::from([("staff".into(), staff.iter().copied().collect())]),
+            }))
+        };
+        let records = [
+            record(&[0]),
+            record(&[]),
+            record(&[]),
+            None,
+            record(&[0, 1]),
+        ];
+        let coverage = Coverage::new(t, &records, "staff", 1, Tz::UTC);
+        assert_eq!(
+            (coverage.tracked, coverage.covered, coverage.coverage),
+            (4, 2, Some(0.5))
+        );
+        assert_eq!(coverage.uncovered, vec![(t + 1, t + 2)]);
+        assert_eq!(coverage.by_hour[0][0], Some(0.5));
+        assert_eq!(coverage.by_hour[0][1], None);
+        let coverage = Coverage::new(t, &records, "staff", 2, Tz::UTC);
+        assert_eq!(coverage.uncovered, vec![(t, t + 2)]);
+    }
+
+    #[test]
+    pub fn unique_cache() {
+        let count = |start| UniqueCount {
+            start,
+            all: 1,
+            categories: HashMap::new(),
+        };
+        let mut cache = UniqueCache::new(2);
+        cache.insert(Period::Day, count(0));
+        cache.insert(Period::Day, count(1440));
+        cache.insert(Period::Week, count(1440 * 4));
+        assert_eq!(cache.get(Period::Day, 0), None);
+        assert_eq!(cache.get(Period::Day, 1440), Some(&count(1440)));
+
+        cache.invalidate(1440 * 5, 1440 * 5);
+        assert_eq!(cache.get(Period::Day, 1440), Some(&count(1440)));
+        assert_eq!(cache.get(Period::Week, 1440 * 4), None);
+        cache.insert(Period::Day, count(1440 * 2));
+        cache.insert(Period::Day, count(1440 * 3));
+        assert_eq!(cache.get(Period::Day, 1440), None);
+    }
+}
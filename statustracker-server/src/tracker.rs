@@ -1,58 +1,356 @@
+use std::{
+    collections::{HashMap, HashSet, VecDeque},
+    sync::Arc,
+    time::SystemTime,
+};
+
 use color_eyre::eyre::{eyre, Result};
 use itertools::Itertools;
-use mongodb::{bson::doc, options::ClientOptions, Client};
 use serde::{Deserialize, Serialize};
 use smol_str::SmolStr;
-use tracing::{debug, info};
+use tokio::sync::broadcast;
+use tracing::{debug, info, warn};
 use uuid::{Bytes, Uuid};
 
-use crate::{config::Config, database::STDatabase, name_to_uuid::name_to_uuid, utils::Category};
+use crate::{
+    config::Config,
+    database::STDatabase,
+    events::{PlayerEvent, TrackerEvent, EVENT_CAPACITY},
+    health,
+    hour::{AbsRecord, GapReason, Record},
+    metrics::{
+        set, CATEGORY_ONLINE_PLAYERS, LAST_WRITE, NAME_MAP_SIZE, ONLINE_PLAYERS, POLL_DURATION,
+        SOURCE_FAILURES,
+    },
+    name_to_uuid::name_to_uuid,
+    outage::Outage,
+    peaks::{PeakId, PeakPeriod, Peaks},
+    scheduler::PollTiming,
+    utils::{get_minute_timestamp, Category, MinuteTimestamp, SecondTimestamp},
+};
 
 pub struct StatusTracker {
     pub config: Config,
     pub name_map: NameMapWrapper,
+    pub seen: SeenIndex,
     pub database: STDatabase,
+    pub poll_timings: VecDeque<PollTiming>,
+    pub current_outage: Option<Outage>,
+    /// Peaks of the current periods
+    pub peaks: HashMap<PeakPeriod, Peaks>,
+    pub events: broadcast::Sender<TrackerEvent>,
+    pub online: OnlinePlayers,
+    /// The latest record, to send the changes of the next one as events.
+    /// Cleared together with `online` when a poll does not get the online players.
+    pub last_record: Option<Arc<AbsRecord>>,
+    /// A `sample_interval` from a config reload, and the start of the hour it takes effect at
+    pub pending_interval: Option<(u32, SecondTimestamp)>,
 }
 
 impl StatusTracker {
     #[tracing::instrument(skip_all)]
     pub async fn new(config: Config) -> Result<Self> {
-        debug!("Checking for `all` category");
-        if config.categories.keys().contains::<Category>(&"all".into()) {
-            return Err(eyre!("Category named `all` found"));
-        }
-        info!("Creating client");
-        let mongodb_uri = if let Ok(mongodb_uri) = std::env::var(&config.mongodb_uri) {
-            mongodb_uri
+        config.validate()?;
+        let database = STDatabase::connect(&config).await?;
+        let name_map = database.get_name_map().await?;
+        let seen = if let Some(seen) = database.get_seen().await? {
+            seen
         } else {
-            config.mongodb_uri.to_string()
+            info!("Building seen index from stored hours");
+            let seen = database.rebuild_seen(name_map.data.len()).await?;
+            database.save_seen(&seen).await?;
+            seen
         };
-        let client = Client::with_options(ClientOptions::parse(mongodb_uri).await?)?;
-        let database = STDatabase(client.database(&config.database_name));
-        info!("Retrieving name_map");
-        let name_map = database
-            .0
-            .collection("name_map")
-            .find_one(doc! {"_id": 0u32})
-            .await?
-            .unwrap_or_default();
+        if !database.has_peaks().await? {
+            info!("Building peaks from stored hours");
+            for peaks in database.rebuild_peaks(name_map.data.len()).await?.values() {
+                database.save_peaks(peaks).await?;
+            }
+        }
+        info!("Retrieving peaks");
+        let now = get_minute_timestamp(SystemTime::now());
+        let mut peaks = HashMap::new();
+        for period in PeakPeriod::ALL {
+            let id = PeakId {
+                period,
+                start: period.start_of(now),
+            };
+            let current = database.get_peaks(id).await?;
+            peaks.insert(period, current.unwrap_or_else(|| Peaks::empty(id)));
+        }
+        set(&NAME_MAP_SIZE, name_map.data.len());
+        info!("Retrieving online players");
+        let recent = database.get_minutes(now - 60 * 24, now - 1).await?;
+        let online = OnlinePlayers::seed(now - 1, &recent, &name_map);
+        let last_record = recent.last().cloned().flatten();
         Ok(Self {
             config,
             name_map,
+            seen,
             database,
+            poll_timings: VecDeque::new(),
+            current_outage: None,
+            peaks,
+            events: broadcast::channel(EVENT_CAPACITY).0,
+            online,
+            last_record,
+            pending_interval: None,
         })
     }
-    pub async fn run(&mut self) -> Result<()> {
-        let names = self.config.pull_from_dynmap().await?;
-        let ids = self.name_map.update_name_map(names).await?;
-        let record = self.config.split_into_categories(ids);
-        self.database.add_record(record).await?;
+    /// Switches to the pending `sample_interval` if polling at `ts` would be in the hour it takes effect at.
+    /// Returns the start of that hour if it was switched.
+    pub fn apply_pending_interval(&mut self, ts: SecondTimestamp) -> Option<SecondTimestamp> {
+        let (interval, at) = self.pending_interval?;
+        if ts < at {
+            return None;
+        }
+        info!(interval, "Changing sample interval");
+        self.pending_interval = None;
+        self.config.sample_interval = interval;
+        Some(at)
+    }
+    pub async fn run(&mut self, ts: SecondTimestamp) -> Result<()> {
+        let _timer = POLL_DURATION.start_timer();
+        health::start_poll(self.config.sample_interval, self.config.maintenance);
+        let result = self.poll(ts).await;
+        health::finish_poll(ts);
+        result
+    }
+    async fn poll(&mut self, ts: SecondTimestamp) -> Result<()> {
+        let resolution = self.config.sample_interval;
+        if self.config.maintenance {
+            info!("In maintenance, not polling");
+            self.online.clear(ts);
+            self.last_record = None;
+            self.database
+                .add_gaps(ts, ts, GapReason::Maintenance, resolution)
+                .await?;
+            return Ok(());
+        }
+        let names = match self.config.pull_from_dynmap().await {
+            Ok(names) => names,
+            Err(e) => {
+                let reason = GapReason::from_source_error(&e);
+                health::source_failed(ts);
+                SOURCE_FAILURES.with_label_values(&[reason.as_str()]).inc();
+                self.add_failure(ts, reason).await;
+                return Err(e);
+            }
+        };
+        let ids = match self.name_map.update_name_map(names.clone()).await {
+            Ok(ids) => ids,
+            Err(e) => {
+                self.add_failure(ts, GapReason::LookupFailed).await;
+                return Err(e);
+            }
+        };
+        set(&NAME_MAP_SIZE, self.name_map.data.len());
+        let record = Arc::new(self.config.split_into_categories(ids.clone()));
+        if let Err(e) = self
+            .database
+            .add_record((*record).clone(), ts, resolution)
+            .await
+        {
+            self.add_failure(ts, GapReason::WriteFailed).await;
+            return Err(e);
+        }
+        if let Some(outage) = self.current_outage.take() {
+            info!(?outage, "Outage ended");
+        }
+        set(&LAST_WRITE, ts);
+        health::record_written(ts);
+        set(&ONLINE_PLAYERS, record.all.len());
+        CATEGORY_ONLINE_PLAYERS.reset();
+        for (cat, ids) in &record.categories {
+            set(
+                &CATEGORY_ONLINE_PLAYERS.with_label_values(&[cat.as_str()]),
+                ids.len(),
+            );
+        }
+        let new_names = ids
+            .iter()
+            .map(|(_, id)| *id)
+            .zip(names.iter().cloned())
+            .collect::<HashMap<_, _>>();
+        self.emit_changes(&record, &new_names, ts);
+        self.last_record = Some(Arc::clone(&record));
+        self.online
+            .update(names.into_iter().zip(ids.iter().copied()), ts);
+        let result = self.save_after_record(&record, &ids, ts).await;
+        if result.is_err() {
+            // The record is stored, so only the failure is counted
+            self.count_failure(ts, GapReason::WriteFailed).await;
+        }
+        result
+    }
+    /// Saves what is derived from a record once the record itself is stored
+    async fn save_after_record(
+        &mut self,
+        record: &AbsRecord,
+        ids: &[(Uuid, usize)],
+        ts: SecondTimestamp,
+    ) -> Result<()> {
+        self.update_peaks(record, ts / 60).await?;
         self.database.save_name_map(&self.name_map).await?;
+        self.seen.update(ids.iter().map(|(_, id)| *id), ts / 60);
+        self.database.save_seen(&self.seen).await?;
+        Ok(())
+    }
+    /// Sends an event to every subscriber, if there are any
+    pub fn emit(&self, event: TrackerEvent) {
+        let _ = self.events.send(event);
+    }
+    fn uuid_of(&self, id: usize) -> Uuid {
+        self.name_map
+            .data
+            .get(id)
+            .map(|a| Uuid::from_bytes(*a))
+            .unwrap_or_default()
+    }
+    /// Sends the players that joined and left since the latest record, and the new counts if they changed
+    fn emit_changes(
+        &self,
+        record: &AbsRecord,
+        names: &HashMap<usize, SmolStr>,
+        ts: SecondTimestamp,
+    ) {
+        let prev = self.last_record.as_deref().cloned().unwrap_or_default();
+        let Record::Delta {
+            joined,
+            joined_categories,
+            left,
+            left_categories,
+        } = Record::delta(&prev, record)
+        else {
+            return;
+        };
+        let player_event =
+            |id: usize, name: Option<SmolStr>, categories: &HashMap<Category, HashSet<usize>>| {
+                PlayerEvent {
+                    time: ts,
+                    uuid: self.uuid_of(id),
+                    name,
+                    categories: categories
+                        .iter()
+                        .filter(|(_, ids)| ids.contains(&id))
+                        .map(|(cat, _)| cat.to_owned())
+                        .sorted()
+                        .collect(),
+                }
+            };
+        for id in left.into_iter().sorted() {
+            let name = self.online.players.get(&id).and_then(|a| a.name.clone());
+            self.emit(TrackerEvent::PlayerLeft(player_event(
+                id,
+                name,
+                &left_categories,
+            )));
+        }
+        for id in joined.into_iter().sorted() {
+            let name = names.get(&id).cloned();
+            self.emit(TrackerEvent::PlayerJoined(player_event(
+                id,
+                name,
+                &joined_categories,
+            )));
+        }
+        let counts = |a: &AbsRecord| {
+            (
+                a.all.len(),
+                a.categories
+                    .iter()
+                    .map(|(cat, ids)| (cat.to_owned(), ids.len()))
+                    .filter(|(_, count)| *count != 0)
+                    .collect::<HashMap<_, _>>(),
+            )
+        };
+        let (all, categories) = counts(record);
+        if self.last_record.is_some() {
+            for cat in prev
+                .categories
+                .iter()
+                .filter(|(cat, ids)| !ids.is_empty() && !categories.contains_key(*cat))
+                .map(|(cat, _)| cat)
+                .sorted()
+            {
+                self.emit(TrackerEvent::CoverageLost {
+                    time: ts,
+                    category: cat.to_owned(),
+                });
+            }
+        }
+        if self.last_record.is_none() || counts(&prev) != (all, categories.clone()) {
+            self.emit(TrackerEvent::CountChanged {
+                time: ts,
+                all,
+                categories,
+            });
+        }
+    }
+    async fn update_peaks(&mut self, record: &AbsRecord, min_ts: MinuteTimestamp) -> Result<()> {
+        for period in PeakPeriod::ALL {
+            let id = PeakId {
+                period,
+                start: period.start_of(min_ts),
+            };
+            if self.peaks.get(&period).is_none_or(|a| a._id != id) {
+                let peaks = self.database.get_peaks(id).await?;
+                self.peaks
+                    .insert(period, peaks.unwrap_or_else(|| Peaks::empty(id)));
+            }
+            let peaks = self.peaks.get_mut(&period).unwrap();
+            let (changed, broken) = peaks.update(record, min_ts);
+            if changed {
+                self.database.save_peaks(peaks).await?;
+            }
+            for broken in broken {
+                info!(?broken, "Peak broken");
+                self.emit(TrackerEvent::PeakBroken(broken));
+            }
+        }
         Ok(())
     }
+    /// Records a poll that did not store a record, clearing the online players and marking the interval as a gap.
+    /// Errors while recording it are only logged, so that the error of the poll is returned.
+    async fn add_failure(&mut self, ts: SecondTimestamp, reason: GapReason) {
+        self.online.clear(ts);
+        self.last_record = None;
+        let resolution = self.config.sample_interval;
+        if let Err(e) = self.database.add_gaps(ts, ts, reason, resolution).await {
+            warn!("Could not store gap: {e}");
+        }
+        self.count_failure(ts, reason).await;
+    }
+    /// Counts a failed poll towards the current outage, and sends `PollFailed` if it is not a retry
+    async fn count_failure(&mut self, ts: SecondTimestamp, reason: GapReason) {
+        let retry = self
+            .current_outage
+            .is_some_and(|outage| outage.latest == ts);
+        let extended = self
+            .current_outage
+            .as_mut()
+            .is_some_and(|outage| outage.extend(ts, reason));
+        if !extended {
+            info!(min_ts = ts / 60, "Outage started");
+            self.current_outage = None;
+        }
+        let outage = *self
+            .current_outage
+            .get_or_insert_with(|| Outage::new(ts, reason, self.config.sample_interval));
+        if !retry {
+            self.emit(TrackerEvent::PollFailed {
+                time: ts,
+                failed_polls: outage.failed_polls,
+                reason,
+            });
+        }
+        if let Err(e) = self.database.save_outage(&outage).await {
+            warn!("Could not save outage: {e}");
+        }
+    }
 }
 
-#[derive(Serialize, Deserialize, Debug, Default)]
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq, Eq)]
 pub struct NameMapWrapper {
     pub _id: u32,
     pub data: Vec<Bytes>,
@@ -71,6 +369,7 @@ impl NameMapWrapper {
                 .iter()
                 .position(|a| a == uuid.as_bytes())
                 .unwrap_or_else(|| {
+                    info!(%name, %uuid, "New player");
                     self.data.push(*uuid.as_bytes());
                     self.data.len() - 1
                 });
@@ -78,4 +377,160 @@ impl NameMapWrapper {
         }
         Ok(uuids)
     }
+    #[must_use]
+    pub fn index_of(&self, uuid: &Uuid) -> Option<usize> {
+        self.data.iter().position(|a| a == uuid.as_bytes())
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct OnlineSession {
+    pub uuid: Uuid,
+    /// `None` until the player is seen by the tracker, if they were online when it started
+    pub name: Option<SmolStr>,
+    /// The minute the current session started
+    pub since: MinuteTimestamp,
+}
+
+/// Players online as of the latest poll, by name map index
+#[derive(Debug, Clone, Default)]
+pub struct OnlinePlayers {
+    /// Second timestamp of the latest poll
+    pub updated: Option<SecondTimestamp>,
+    pub players: HashMap<usize, OnlineSession>,
+}
+impl OnlinePlayers {
+    /// Players online in the last of `records`, which end at `to`, with sessions continued as far back as they go
+    #[must_use]
+    pub fn seed(
+        to: MinuteTimestamp,
+        records: &[Option<Arc<AbsRecord>>],
+        name_map: &NameMapWrapper,
+    ) -> Self {
+        let Some(Some(last)) = records.last() else {
+            return Self::default();
+        };
+        let players = last
+            .all
+            .iter()
+            .filter_map(|id| {
+                let run = records
+                    .iter()
+                    .rev()
+                    .take_while(|a| a.as_ref().is_some_and(|a| a.all.contains(id)))
+                    .count() as u64;
+                Some((
+                    *id,
+                    OnlineSession {
+                        uuid: Uuid::from_bytes(*name_map.data.get(*id)?),
+                        name: None,
+                        since: to + 1 - run,
+                    },
+                ))
+            })
+            .collect();
+        Self {
+            updated: Some(to * 60),
+            players,
+        }
+    }
+    /// Replaces the online players with those from a poll at `ts`, keeping the start of continuing sessions
+    pub fn update<I: IntoIterator<Item = (SmolStr, (Uuid, usize))>>(
+        &mut self,
+        players: I,
+        ts: SecondTimestamp,
+    ) {
+        let mut previous = std::mem::take(&mut self.players);
+        self.players = players
+            .into_iter()
+            .map(|(name, (uuid, id))| {
+                let since = previous.remove(&id).map_or(ts / 60, |a| a.since);
+                (
+                    id,
+                    OnlineSession {
+                        uuid,
+                        name: Some(name),
+                        since,
+                    },
+                )
+            })
+            .collect();
+        self.updated = Some(ts);
+    }
+    /// Forgets every session, after a poll at `ts` that did not get the online players
+    pub fn clear(&mut self, ts: SecondTimestamp) {
+        self.players.clear();
+        self.updated = Some(ts);
+    }
+}
+
+/// The first and last minute each player was seen online, indexed the same way as the name map
+#[derive(Serialize, Deserialize, Debug, Default)]
+pub struct SeenIndex {
+    pub _id: u32,
+    pub first: Vec<Option<MinuteTimestamp>>,
+    pub last: Vec<Option<MinuteTimestamp>>,
+}
+impl SeenIndex {
+    pub fn update<I: IntoIterator<Item = usize>>(&mut self, ids: I, min_ts: MinuteTimestamp) {
+        for id in ids {
+            if self.first.len() <= id {
+                self.first.resize(id + 1, None);
+                self.last.resize(id + 1, None);
+            }
+            self.first[id] = Some(self.first[id].map_or(min_ts, |a| a.min(min_ts)));
+            self.last[id] = Some(self.last[id].map_or(min_ts, |a| a.max(min_ts)));
+        }
+    }
+    #[must_use]
+    pub fn get(&self, id: usize) -> (Option<MinuteTimestamp>, Option<MinuteTimestamp>) {
+        (
+            self.first.get(id).copied().flatten(),
+            self.last.get(id).copied().flatten(),
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        collections::{HashMap, HashSet},
+        sync::Arc,
+    };
+
+    use uuid::Uuid;
+
+    use crate::{
+        hour::AbsRecord,
+        tracker::{NameMapWrapper, OnlinePlayers},
+    };
+
+    #[test]
+    pub fn online_sessions() {
+        let name_map = NameMapWrapper {
+            _id: 0,
+            data: vec![[0; 16], [1; 16]],
+        };
+        let record = |all: &[usize]| {
+            Some(Arc::new(AbsRecord {
+                all: all.iter().copied().collect::<HashSet<_>>(),
+                categories: HashMap::new(),
+            }))
+        };
+        let mut online = OnlinePlayers::seed(
+            12,
+            &[record(&[0]), None, record(&[0, 1]), record(&[0, 1])],
+            &name_map,
+        );
+        assert_eq!(online.players[&0].since, 11);
+        assert_eq!(online.players[&1].since, 11);
+
+        online.update([("b".into(), (Uuid::from_bytes([1; 16]), 1))], 13 * 60);
+        assert_eq!(online.players.len(), 1);
+        assert_eq!(online.players[&1].since, 11);
+        assert_eq!(online.players[&1].name, Some("b".into()));
+        online.clear(14 * 60);
+        online.update([("b".into(), (Uuid::from_bytes([1; 16]), 1))], 15 * 60);
+        assert_eq!(online.players[&1].since, 15);
+    }
 }
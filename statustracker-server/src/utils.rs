@@ -5,8 +5,19 @@ use smol_str::SmolStr;
 
 pub type HourTimestamp = u32;
 pub type MinuteTimestamp = u64;
+pub type SecondTimestamp = u64;
 pub type Category = SmolStr;
 
+#[must_use]
+pub fn get_second_timestamp(t: SystemTime) -> SecondTimestamp {
+    t.duration_since(SystemTime::UNIX_EPOCH).unwrap().as_secs()
+}
+
+#[must_use]
+pub fn get_minute_timestamp(t: SystemTime) -> MinuteTimestamp {
+    t.duration_since(SystemTime::UNIX_EPOCH).unwrap().as_secs() / 60
+}
+
 #[must_use]
 pub fn get_hour_timestamp(t: SystemTime) -> HourTimestamp {
     (t.duration_since(SystemTime::UNIX_EPOCH).unwrap().as_secs() / 60 / 60) as HourTimestamp
@@ -0,0 +1,405 @@
+use std::{collections::HashMap, sync::Arc, time::Duration};
+
+use color_eyre::eyre::{eyre, Result};
+use serde_json::Value;
+use tokio::{
+    sync::{broadcast::error::RecvError, mpsc, RwLock},
+    task::JoinHandle,
+    time::Instant,
+};
+use tracing::{debug, error, info, warn};
+use url::Url;
+
+use crate::{
+    config::{WebhookConfig, WebhookEvent},
+    events::TrackerEvent,
+    peaks::PeakId,
+    tracker::StatusTracker,
+    utils::Category,
+};
+
+const QUEUE_CAPACITY: usize = 256;
+const MAX_ATTEMPTS: u32 = 5;
+/// Delay before the first retry, doubled after every failed attempt
+const RETRY_DELAY: Duration = Duration::from_secs(2);
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct Delivery {
+    url: Url,
+    content: String,
+    attempts: u32,
+}
+
+/// Peaks that are still rising, by webhook and the peak they are for
+type DebounceKey = (Url, PeakId, Option<Category>);
+
+/// Holds deliveries back until nothing newer replaces them for a while
+#[derive(Debug, Default)]
+struct Debouncer {
+    pending: HashMap<DebounceKey, (Delivery, Instant)>,
+}
+impl Debouncer {
+    /// Replaces the pending delivery for `key`, and waits `delay` again
+    fn push(&mut self, key: DebounceKey, delivery: Delivery, now: Instant, delay: Duration) {
+        self.pending.insert(key, (delivery, now + delay));
+    }
+    fn next_deadline(&self) -> Option<Instant> {
+        self.pending.values().map(|(_, deadline)| *deadline).min()
+    }
+    /// Drops the deliveries of webhooks no longer configured to send peaks after a config reload,
+    /// and returns the ones of webhooks that no longer debounce them, to be sent now
+    fn reconfigure(&mut self, webhooks: &[WebhookConfig]) -> Vec<Delivery> {
+        let mut undebounced = vec![];
+        self.pending.retain(|(url, ..), (delivery, _)| {
+            let Some(webhook) = webhooks
+                .iter()
+                .find(|a| a.url == *url && a.events.contains(&WebhookEvent::PeakBroken))
+            else {
+                return false;
+            };
+            if webhook.peak_debounce > 0 {
+                return true;
+            }
+            undebounced.push(delivery.to_owned());
+            false
+        });
+        undebounced
+    }
+    /// Removes and returns the deliveries that have waited long enough
+    fn due(&mut self, now: Instant) -> Vec<Delivery> {
+        let mut due = vec![];
+        self.pending.retain(|_, (delivery, deadline)| {
+            if *deadline > now {
+                return true;
+            }
+            due.push(delivery.to_owned());
+            false
+        });
+        due
+    }
+}
+
+const fn default_template(event: WebhookEvent) -> &'static str {
+    match event {
+        WebhookEvent::PlayerJoined => "{name} joined",
+        WebhookEvent::CoverageLost => "Nobody in {category} is online",
+        WebhookEvent::PeakBroken => "New {id.period} peak of {peak.count} players",
+        WebhookEvent::PollFailed => "Polling failed {failed_polls} times in a row ({reason})",
+    }
+}
+
+/// Replaces every `{field}` in `template` with that field of `value`.
+/// Nested fields are separated by `.`, and fields that do not exist are left as they are.
+#[must_use]
+pub fn render(template: &str, value: &Value) -> String {
+    let mut out = String::with_capacity(template.len());
+    let mut rest = template;
+    while let Some((before, after)) = rest.split_once('{') {
+        out.push_str(before);
+        let Some((field, after)) = after.split_once('}') else {
+            out.push('{');
+            rest = after;
+            break;
+        };
+        match field
+            .split('.')
+            .try_fold(value, |value, key| value.get(key))
+        {
+            Some(Value::String(a)) => out.push_str(a),
+            Some(Value::Null) => {}
+            Some(Value::Array(a)) => out.push_str(
+                &a.iter()
+                    .map(|a| a.as_str().map_or_else(|| a.to_string(), ToOwned::to_owned))
+                    .collect::<Vec<_>>()
+                    .join(", "),
+            ),
+            Some(a) => out.push_str(&a.to_string()),
+            None => {
+                out.push('{');
+                out.push_str(field);
+                out.push('}');
+            }
+        }
+        rest = after;
+    }
+    out.push_str(rest);
+    out
+}
+
+impl WebhookConfig {
+    /// The message to send for an event, if this webhook wants it
+    #[must_use]
+    pub fn message(&self, event: &TrackerEvent) -> Option<String> {
+        let kind = match event {
+            TrackerEvent::PlayerJoined(a)
+                if self.players.is_empty() || self.players.contains(&a.uuid) =>
+            {
+                WebhookEvent::PlayerJoined
+            }
+            TrackerEvent::CoverageLost { category, .. }
+                if self.categories.is_empty() || self.categories.contains(category) =>
+            {
+                WebhookEvent::CoverageLost
+            }
+            TrackerEvent::PeakBroken(a) if self.peak_periods.contains(&a.id.period) => {
+                WebhookEvent::PeakBroken
+            }
+            TrackerEvent::PollFailed { failed_polls, .. } if *failed_polls == self.failed_polls => {
+                WebhookEvent::PollFailed
+            }
+            _ => return None,
+        };
+        if !self.events.contains(&kind) {
+            return None;
+        }
+        let template = self
+            .templates
+            .get(&kind)
+            .map_or_else(|| default_template(kind), String::as_str);
+        Some(render(template, &serde_json::to_value(event).ok()?))
+    }
+}
+
+async fn deliver(client: &reqwest::Client, delivery: &Delivery) -> Result<()> {
+    let response = client
+        .post(delivery.url.to_owned())
+        .json(&serde_json::json!({ "content": delivery.content }))
+        .send()
+        .await?;
+    let status = response.status();
+    if status.is_success() {
+        return Ok(());
+    }
+    Err(eyre!("Webhook responded with {status}"))
+}
+
+/// Starts sending events to the webhooks in the config, retrying failed deliveries with backoff
+pub fn spawn_webhooks(tracker: &Arc<RwLock<StatusTracker>>) -> Vec<JoinHandle<()>> {
+    let (queue, mut deliveries) = mpsc::channel::<Delivery>(QUEUE_CAPACITY);
+
+    let retry_queue = queue.clone();
+    let sender = tokio::spawn(async move {
+        let client = reqwest::Client::new();
+        while let Some(mut delivery) = deliveries.recv().await {
+            delivery.attempts += 1;
+            let Err(e) = deliver(&client, &delivery).await else {
+                debug!(url = %delivery.url, "Delivered webhook");
+                continue;
+            };
+            if delivery.attempts >= MAX_ATTEMPTS {
+                error!(url = %delivery.url, attempts = delivery.attempts, "Giving up on webhook: {e}");
+                continue;
+            }
+            warn!(url = %delivery.url, attempts = delivery.attempts, "Retrying webhook: {e}");
+            let delay = RETRY_DELAY * 2u32.pow(delivery.attempts - 1);
+            let retry_queue = retry_queue.clone();
+            tokio::spawn(async move {
+                tokio::time::sleep(delay).await;
+                let _ = retry_queue.send(delivery).await;
+            });
+        }
+    });
+
+    let tracker = Arc::clone(tracker);
+    let dispatcher = tokio::spawn(async move {
+        let mut events = tracker.read().await.events.subscribe();
+        let mut debouncer = Debouncer::default();
+        let send = |delivery: Delivery| {
+            if queue.try_send(delivery).is_err() {
+                error!("Webhook queue is full, dropping message");
+            }
+        };
+        let mut webhooks = tracker.read().await.config.webhooks.clone();
+        loop {
+            {
+                let tracker = tracker.read().await;
+                if tracker.config.webhooks != webhooks {
+                    webhooks.clone_from(&tracker.config.webhooks);
+                    debouncer.reconfigure(&webhooks).into_iter().for_each(send);
+                }
+            }
+            let deadline = debouncer.next_deadline();
+            let event = tokio::select! {
+                event = events.recv() => event,
+                () = tokio::time::sleep_until(deadline.unwrap_or_else(Instant::now)),
+                    if deadline.is_some() =>
+                {
+                    debouncer.due(Instant::now()).into_iter().for_each(send);
+                    continue;
+                }
+            };
+            let event = match event {
+                Ok(event) => event,
+                Err(RecvError::Lagged(skipped)) => {
+                    warn!(skipped, "Webhooks lagged behind");
+                    continue;
+                }
+                Err(RecvError::Closed) => break,
+            };
+            let messages = tracker
+                .read()
+                .await
+                .config
+                .webhooks
+                .iter()
+                .filter_map(|webhook| {
+                    Some((
+                        webhook.url.to_owned(),
+                        webhook.peak_debounce,
+                        webhook.message(&event)?,
+                    ))
+                })
+                .collect::<Vec<_>>();
+            for (url, peak_debounce, content) in messages {
+                let delivery = Delivery {
+                    url: url.to_owned(),
+                    content,
+                    attempts: 0,
+                };
+                match &event {
+                    TrackerEvent::PeakBroken(peak) if peak_debounce > 0 => {
+                        debug!(%url, "Debouncing webhook");
+                        debouncer.push(
+                            (url, peak.id, peak.category.to_owned()),
+                            delivery,
+                            Instant::now(),
+                            Duration::from_secs(peak_debounce),
+                        );
+                    }
+                    _ => {
+                        info!(%url, event = event.name(), "Queueing webhook");
+                        send(delivery);
+                    }
+                }
+            }
+        }
+    });
+
+    vec![sender, dispatcher]
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{collections::HashMap, time::Duration};
+
+    use tokio::time::Instant;
+    use uuid::Uuid;
+
+    use crate::{
+        config::{WebhookConfig, WebhookEvent},
+        events::{PlayerEvent, TrackerEvent},
+        hour::GapReason,
+        peaks::{PeakId, PeakPeriod},
+        webhooks::{render, Debouncer, Delivery},
+    };
+
+    #[test]
+    pub fn render_templates() {
+        let value = serde_json::json!({
+            "name": "Steve",
+            "categories": ["staff", "builder"],
+            "peak": {"count": 12},
+            "category": null,
+        });
+        assert_eq!(
+            render(
+                "{name} ({categories}) {peak.count}{category} {missing} {",
+                &value
+            ),
+            "Steve (staff, builder) 12 {missing} {"
+        );
+    }
+    #[test]
+    pub fn filter_events() {
+        let uuid = Uuid::from_bytes([1; 16]);
+        let webhook = WebhookConfig {
+            url: "https://example.com".parse().unwrap(),
+            events: vec![WebhookEvent::PlayerJoined, WebhookEvent::PollFailed],
+            players: vec![uuid],
+            categories: vec![],
+            peak_periods: vec![],
+            peak_debounce: 0,
+            failed_polls: 2,
+            templates: HashMap::from([(WebhookEvent::PlayerJoined, "{name} is here".into())]),
+        };
+        let joined = |uuid| {
+            TrackerEvent::PlayerJoined(PlayerEvent {
+                time: 0,
+                uuid,
+                name: Some("Steve".into()),
+                categories: vec![],
+            })
+        };
+        assert_eq!(webhook.message(&joined(uuid)), Some("Steve is here".into()));
+        assert_eq!(webhook.message(&joined(Uuid::nil())), None);
+        let failed = |failed_polls| TrackerEvent::PollFailed {
+            time: 0,
+            failed_polls,
+            reason: GapReason::SourceUnreachable,
+        };
+        assert_eq!(webhook.message(&failed(1)), None);
+        assert_eq!(
+            webhook.message(&failed(2)),
+            Some("Polling failed 2 times in a row (source_unreachable)".into())
+        );
+        assert_eq!(
+            webhook.message(&TrackerEvent::CoverageLost {
+                time: 0,
+                category: "staff".into(),
+            }),
+            None
+        );
+    }
+
+    #[test]
+    pub fn debounce_peaks() {
+        let url: url::Url = "https://example.com".parse().unwrap();
+        let key = (
+            url.to_owned(),
+            PeakId {
+                period: PeakPeriod::AllTime,
+                start: 0,
+            },
+            None,
+        );
+        let delivery = |content: &str| Delivery {
+            url: url.to_owned(),
+            content: content.into(),
+            attempts: 0,
+        };
+        let now = Instant::now();
+        let delay = Duration::from_secs(600);
+        let mut debouncer = Debouncer::default();
+        debouncer.push(key.to_owned(), delivery("10 players"), now, delay);
+        debouncer.push(
+            key.to_owned(),
+            delivery("11 players"),
+            now + delay / 2,
+            delay,
+        );
+        assert_eq!(debouncer.next_deadline(), Some(now + delay * 3 / 2));
+        assert_eq!(debouncer.due(now + delay), vec![]);
+        assert_eq!(debouncer.due(now + delay * 2), vec![delivery("11 players")]);
+        assert_eq!(debouncer.next_deadline(), None);
+
+        let webhook = |peak_debounce| WebhookConfig {
+            url: url.to_owned(),
+            events: vec![WebhookEvent::PeakBroken],
+            players: vec![],
+            categories: vec![],
+            peak_periods: vec![PeakPeriod::AllTime],
+            peak_debounce,
+            failed_polls: 3,
+            templates: HashMap::new(),
+        };
+        debouncer.push(key.to_owned(), delivery("12 players"), now, delay);
+        assert_eq!(debouncer.reconfigure(&[webhook(300)]), vec![]);
+        assert_eq!(
+            debouncer.reconfigure(&[webhook(0)]),
+            vec![delivery("12 players")]
+        );
+        debouncer.push(key, delivery("13 players"), now, delay);
+        assert_eq!(debouncer.reconfigure(&[]), vec![]);
+        assert_eq!(debouncer.next_deadline(), None);
+    }
+}